@@ -0,0 +1,40 @@
+extern crate mini_http;
+extern crate simple_logger;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use simple_logger::SimpleLogger;
+
+/// Listener of the admin port, after the public one
+const ADMIN: usize = 1;
+
+/// Serve the public site on http://127.0.0.1:3000 and IPv6, and how many requests
+/// it got on the admin port http://127.0.0.1:3001, only reachable locally
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    SimpleLogger::new().init().unwrap();
+
+    let served = Arc::new(AtomicUsize::new(0));
+    mini_http::Server::new("127.0.0.1:3000")?
+        .listen("127.0.0.1:3001")
+        .listen("[::1]:3000")
+        .start(move |request| {
+            let body = if request.listener() == ADMIN {
+                format!("{} requests served\n", served.load(Ordering::Relaxed))
+            } else {
+                served.fetch_add(1, Ordering::Relaxed);
+                "Hello world!\n".to_string()
+            };
+            mini_http::Response::builder()
+                .status(200)
+                .body(body.into_bytes())
+                .unwrap()
+        })?;
+    Ok(())
+}
+
+pub fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+    }
+}
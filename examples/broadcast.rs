@@ -0,0 +1,37 @@
+extern crate mini_http;
+extern crate simple_logger;
+
+use mini_http::{Broadcast, Event, EventStream};
+use simple_logger::SimpleLogger;
+
+/// Follow a topic on http://127.0.0.1:3000/<topic>, and publish to it with
+/// `curl -d 'hello' http://127.0.0.1:3000/<topic>`
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    SimpleLogger::new().init().unwrap();
+
+    let broadcast = Broadcast::new();
+    mini_http::Server::new("127.0.0.1:3000")?
+        .tcp_nodelay(true)
+        .start(move |request| {
+            let topic = request.uri().path().trim_start_matches('/');
+            if request.method() == mini_http::method::Method::POST {
+                let data = String::from_utf8_lossy(request.body()).into_owned();
+                let subscribers = broadcast.publish(topic, Event::data(data));
+                return mini_http::Response::builder()
+                    .status(200)
+                    .body(format!("Sent to {} subscribers\n", subscribers).into())
+                    .unwrap();
+            }
+            let (events, response) = EventStream::response();
+            events.send(Event::data(format!("Following {}", topic)));
+            broadcast.subscribe(topic, &events);
+            response
+        })?;
+    Ok(())
+}
+
+pub fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+    }
+}
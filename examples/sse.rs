@@ -0,0 +1,50 @@
+extern crate mini_http;
+extern crate simple_logger;
+
+use std::thread;
+use std::time::Duration;
+
+use mini_http::{Event, EventStream};
+use simple_logger::SimpleLogger;
+
+/// Count up every second on http://127.0.0.1:3000/events, clients reconnecting
+/// pick up after the last event they received
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    SimpleLogger::new().init().unwrap();
+
+    mini_http::Server::new("127.0.0.1:3000")?
+        .tcp_nodelay(true)
+        .start(|request| {
+            if request.uri().path() != "/events" {
+                return mini_http::Response::builder()
+                    .status(404)
+                    .body("Try /events\n".into())
+                    .unwrap();
+            }
+            let last: u64 = request
+                .last_event_id()
+                .and_then(|id| id.parse().ok())
+                .unwrap_or(0);
+            let (events, response) = EventStream::response();
+            thread::spawn(move || {
+                for n in last + 1.. {
+                    let event = Event::data(format!("tick {}", n))
+                        .event("tick")
+                        .id(n.to_string());
+                    if !events.send(event) {
+                        // the client went away
+                        break;
+                    }
+                    thread::sleep(Duration::from_secs(1));
+                }
+            });
+            response
+        })?;
+    Ok(())
+}
+
+pub fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+    }
+}
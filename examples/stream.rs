@@ -0,0 +1,28 @@
+extern crate log;
+extern crate mini_http;
+extern crate simple_logger;
+
+use simple_logger::SimpleLogger;
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    SimpleLogger::new().init().unwrap();
+
+    mini_http::Server::new("127.0.0.1:3000")?
+        .tcp_nodelay(true)
+        .start(|_req| {
+            // produced one line at a time, never held in memory as a whole
+            let lines = (0..100_000).map(|n| format!("line {}\n", n).into_bytes());
+            mini_http::Response::builder()
+                .status(200)
+                .header("Content-Type", "text/plain")
+                .body(mini_http::Body::from_chunks(lines))
+                .unwrap()
+        })?;
+    Ok(())
+}
+
+pub fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {:?}", e);
+    }
+}
@@ -0,0 +1,31 @@
+extern crate log;
+extern crate mini_http;
+extern crate simple_logger;
+
+use simple_logger::SimpleLogger;
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    SimpleLogger::new().init().unwrap();
+
+    mini_http::Server::new("127.0.0.1:3000")?
+        .tcp_nodelay(true)
+        .worker_threads(4)
+        .reactors(2)
+        .start_threaded(|request| {
+            // a slow handler only holds up its own worker thread
+            if request.uri().path() == "/slow" {
+                std::thread::sleep(std::time::Duration::from_secs(1));
+            }
+            mini_http::Response::builder()
+                .status(200)
+                .body(format!("Hello from {}!\n", request.uri().path()))
+                .unwrap()
+        })?;
+    Ok(())
+}
+
+pub fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {:?}", e);
+    }
+}
@@ -0,0 +1,32 @@
+extern crate log;
+extern crate mini_http;
+extern crate simple_logger;
+
+use simple_logger::SimpleLogger;
+
+/// Serve https://127.0.0.1:3443 with the certificate and key given as arguments,
+/// e.g. `cargo run --example tls --features tls -- cert.pem key.pem`
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    SimpleLogger::new().init().unwrap();
+
+    let mut args = std::env::args().skip(1);
+    let cert = args.next().unwrap_or_else(|| "cert.pem".into());
+    let key = args.next().unwrap_or_else(|| "key.pem".into());
+
+    mini_http::Server::new("127.0.0.1:3443")?
+        .tcp_nodelay(true)
+        .tls(&cert, &key)?
+        .start(|_req| {
+            mini_http::Response::builder()
+                .status(200)
+                .body(b"Hello over TLS!\n".to_vec())
+                .unwrap()
+        })?;
+    Ok(())
+}
+
+pub fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {:?}", e);
+    }
+}
@@ -0,0 +1,30 @@
+extern crate mini_http;
+extern crate simple_logger;
+
+use simple_logger::SimpleLogger;
+
+/// Greet the processes connecting to /tmp/mini_http.sock, e.g. with
+/// `curl --unix-socket /tmp/mini_http.sock http://localhost/`
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    SimpleLogger::new().init().unwrap();
+
+    mini_http::Server::unix("/tmp/mini_http.sock")?
+        .unix_permissions(0o660)
+        .start(|request| {
+            let greeting = match request.peer_credentials() {
+                Some(peer) => format!("Hello uid {}, gid {}!\n", peer.uid, peer.gid),
+                None => "Hello stranger!\n".to_string(),
+            };
+            mini_http::Response::builder()
+                .status(200)
+                .body(greeting.into_bytes())
+                .unwrap()
+        })?;
+    Ok(())
+}
+
+pub fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+    }
+}
@@ -0,0 +1,61 @@
+extern crate mini_http;
+extern crate simple_logger;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::thread;
+
+use mini_http::Upgraded;
+use simple_logger::SimpleLogger;
+
+/// Switch connections asking for `Upgrade: shout` to a line based protocol
+/// answering each line in upper case, plain HTTP requests get a hint
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    SimpleLogger::new().init().unwrap();
+
+    mini_http::Server::new("127.0.0.1:3000")?.start(|request| {
+        let wants_shout = request
+            .headers()
+            .get(mini_http::header::UPGRADE)
+            .map(|protocol| protocol == "shout")
+            .unwrap_or(false);
+        if !wants_shout {
+            return mini_http::Response::builder()
+                .status(426)
+                .header(mini_http::header::UPGRADE, "shout")
+                .body("Send `Upgrade: shout`\n".into())
+                .unwrap();
+        }
+        Upgraded::response("shout", |upgraded| {
+            thread::spawn(move || {
+                let (stream, buffered) = match upgraded.into_std() {
+                    Ok(parts) => parts,
+                    Err(e) => return eprintln!("Error: {}", e),
+                };
+                let mut writer = match stream.try_clone() {
+                    Ok(writer) => writer,
+                    Err(e) => return eprintln!("Error: {}", e),
+                };
+                // the client may have sent its first lines along with the request
+                let reader = BufReader::new(buffered.as_slice().chain(stream));
+                for line in reader.lines() {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(_) => break,
+                    };
+                    if writeln!(writer, "{}", line.to_uppercase()).is_err() {
+                        break;
+                    }
+                }
+            });
+        })
+        // a constant protocol name, always a valid header value
+        .unwrap()
+    })?;
+    Ok(())
+}
+
+pub fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+    }
+}
@@ -0,0 +1,60 @@
+extern crate log;
+extern crate mini_http;
+extern crate simple_logger;
+
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use mini_http::{Message, WebSocket};
+use simple_logger::SimpleLogger;
+
+/// Echo the messages sent to ws://127.0.0.1:3000/echo, numbering them, and push
+/// the time every 5 seconds from another thread
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    SimpleLogger::new().init().unwrap();
+
+    mini_http::Server::new("127.0.0.1:3000")?
+        .tcp_nodelay(true)
+        .websocket(|head| {
+            if head.uri().path() != "/echo" {
+                return None;
+            }
+            let mut count = 0;
+            Some(move |ws: &mut WebSocket, message: Message| match message {
+                Message::Open => {
+                    let sender = ws.sender();
+                    thread::spawn(move || loop {
+                        thread::sleep(Duration::from_secs(5));
+                        let now = SystemTime::now()
+                            .duration_since(SystemTime::UNIX_EPOCH)
+                            .unwrap();
+                        if !sender.send(format!("time: {}", now.as_secs())) {
+                            // the connection is closing
+                            break;
+                        }
+                    });
+                }
+                Message::Text(text) => {
+                    count += 1;
+                    ws.send(format!("{}: {}", count, text));
+                }
+                Message::Binary(data) => ws.send(data),
+                Message::Close(code, reason) => {
+                    log::info!("closed after {} messages: {:?} {}", count, code, reason)
+                }
+            })
+        })
+        .start(|_req| {
+            mini_http::Response::builder()
+                .status(200)
+                .body(b"Connect to ws://127.0.0.1:3000/echo\n".to_vec())
+                .unwrap()
+        })?;
+    Ok(())
+}
+
+pub fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+    }
+}
@@ -0,0 +1,168 @@
+use std::fmt;
+use std::io::{self, Read};
+
+use sse::Receiver;
+
+/// Size of the buffer filled from an `io::Read` for each chunk
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+type Producer = Box<dyn FnMut() -> io::Result<Option<Vec<u8>>> + Send>;
+
+/// Body of a `Response` returned by handlers.
+///
+/// A body is either fully materialized bytes, sent with a `Content-Length`, or a
+/// stream of chunks produced on demand while the socket accepts more data, sent
+/// with `Transfer-Encoding: chunked` (unless the handler set a `Content-Length`
+/// itself). Handlers can keep returning a `Response<Vec<u8>>`, anything
+/// `Into<Body>` is accepted. The events of an `EventStream` are streamed as
+/// they're sent.
+pub struct Body {
+    kind: Kind,
+}
+enum Kind {
+    Full(Vec<u8>),
+    Stream(Producer),
+    Events(Receiver),
+}
+impl Body {
+    /// An empty, fully materialized body
+    pub fn empty() -> Self {
+        Self {
+            kind: Kind::Full(Vec::new()),
+        }
+    }
+
+    /// Stream the items of an iterator, each item is sent as a chunk
+    pub fn from_chunks<I>(chunks: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<Vec<u8>>,
+        I::IntoIter: Send + 'static,
+    {
+        let mut chunks = chunks.into_iter();
+        Self::from_fn(move || Ok(chunks.next().map(Into::into)))
+    }
+
+    /// Stream the contents of a reader until it reaches EOF
+    pub fn from_reader<R>(mut reader: R) -> Self
+    where
+        R: Read + Send + 'static,
+    {
+        Self::from_fn(move || {
+            let mut buf = vec![0; READ_CHUNK_SIZE];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) => return Ok(None),
+                    Ok(n) => {
+                        buf.truncate(n);
+                        return Ok(Some(buf));
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        })
+    }
+
+    /// Stream chunks from a producer callback. The callback is called each time the
+    /// socket can take more data, until it returns `Ok(None)`. Returning an error
+    /// aborts the response and closes the connection
+    pub fn from_fn<F>(producer: F) -> Self
+    where
+        F: FnMut() -> io::Result<Option<Vec<u8>>> + Send + 'static,
+    {
+        Self {
+            kind: Kind::Stream(Box::new(producer)),
+        }
+    }
+
+    /// The body of an event stream response, see `EventStream::response`
+    pub(crate) fn from_events(receiver: Receiver) -> Self {
+        Self {
+            kind: Kind::Events(receiver),
+        }
+    }
+
+    /// Whether this body is produced incrementally
+    pub fn is_stream(&self) -> bool {
+        match self.kind {
+            Kind::Full(_) => false,
+            Kind::Stream(_) | Kind::Events(_) => true,
+        }
+    }
+
+    /// Length of a fully materialized body, `None` for streams
+    pub fn content_length(&self) -> Option<usize> {
+        match self.kind {
+            Kind::Full(ref bytes) => Some(bytes.len()),
+            Kind::Stream(_) | Kind::Events(_) => None,
+        }
+    }
+
+    /// Bytes of a fully materialized body, empty for streams
+    pub(crate) fn bytes(&self) -> &[u8] {
+        match self.kind {
+            Kind::Full(ref bytes) => bytes,
+            Kind::Stream(_) | Kind::Events(_) => &[],
+        }
+    }
+
+    /// The event stream this body is made of, if any
+    pub(crate) fn events(&self) -> Option<&Receiver> {
+        match self.kind {
+            Kind::Events(ref receiver) => Some(receiver),
+            _ => None,
+        }
+    }
+
+    /// Whether an event stream has nothing to produce until more events are sent.
+    /// Its next chunk would be empty
+    pub(crate) fn is_waiting(&self) -> bool {
+        self.events().map(Receiver::is_waiting).unwrap_or(false)
+    }
+
+    /// Produce the next chunk of a streamed body, `None` once it's exhausted
+    pub(crate) fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match self.kind {
+            Kind::Full(_) => Ok(None),
+            Kind::Stream(ref mut producer) => producer(),
+            Kind::Events(ref receiver) => Ok(receiver.next_chunk()),
+        }
+    }
+}
+impl Default for Body {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            Kind::Full(ref bytes) => write!(f, "Body({} bytes)", bytes.len()),
+            Kind::Stream(_) => write!(f, "Body(stream)"),
+            Kind::Events(_) => write!(f, "Body(events)"),
+        }
+    }
+}
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self {
+            kind: Kind::Full(bytes),
+        }
+    }
+}
+impl From<&'static [u8]> for Body {
+    fn from(bytes: &'static [u8]) -> Self {
+        bytes.to_vec().into()
+    }
+}
+impl From<String> for Body {
+    fn from(s: String) -> Self {
+        s.into_bytes().into()
+    }
+}
+impl From<&'static str> for Body {
+    fn from(s: &'static str) -> Self {
+        s.as_bytes().into()
+    }
+}
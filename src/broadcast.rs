@@ -0,0 +1,141 @@
+//! Topics fanning events out to many event streams at once. Publishing only queues
+//! the events, the event loops of the subscribed connections write them out.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use sse::{Event, EventStream, Offer};
+
+/// Bytes of events a subscriber may have waiting to be written before it's dropped
+const DEFAULT_QUEUE_LIMIT: usize = 256 * 1024;
+
+/// Publishes events to the event streams subscribed to a topic, from any thread.
+///
+/// Clones publish to the same topics. Each subscriber queues the events it hasn't
+/// written yet, up to `queue_limit` bytes. A subscriber whose client doesn't keep
+/// up is dropped once its queue is full: the events still queued are discarded
+/// and its stream is closed, so its client reconnects, possibly resuming after
+/// the `Request::last_event_id` it got.
+///
+/// ```rust,no_run
+/// # fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let broadcast = mini_http::Broadcast::new();
+/// let publisher = broadcast.clone();
+/// std::thread::spawn(move || loop {
+///     publisher.publish("clock", mini_http::Event::data(format!("{:?}", std::time::SystemTime::now())));
+///     std::thread::sleep(std::time::Duration::from_secs(1));
+/// });
+/// mini_http::Server::new("127.0.0.1:3000")?
+///     .start(move |_request| {
+///         let (events, response) = mini_http::EventStream::response();
+///         broadcast.subscribe("clock", &events);
+///         response
+///     })?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Broadcast {
+    topics: Arc<Mutex<HashMap<String, Vec<EventStream>>>>,
+    queue_limit: usize,
+}
+impl Broadcast {
+    /// A broadcast whose subscribers may each have up to `256 KiB` of events waiting
+    pub fn new() -> Self {
+        Self::with_queue_limit(DEFAULT_QUEUE_LIMIT)
+    }
+
+    /// A broadcast whose subscribers may each have up to `queue_limit` bytes of
+    /// events waiting to be written
+    pub fn with_queue_limit(queue_limit: usize) -> Self {
+        Self {
+            topics: Arc::new(Mutex::new(HashMap::new())),
+            queue_limit,
+        }
+    }
+
+    /// Send the events published to `topic` to `events` as well, until its client
+    /// goes away or it's dropped for falling behind. The broadcast keeps the stream
+    /// open meanwhile, it may still be sent other events directly.
+    ///
+    /// Subscribers of `topic` whose stream closed are dropped first, so a topic
+    /// rarely published to doesn't keep the streams of departed clients around
+    pub fn subscribe(&self, topic: &str, events: &EventStream) {
+        let mut topics = self.topics.lock().unwrap();
+        let subscribers = topics.entry(topic.to_string()).or_default();
+        subscribers.retain(|events| !events.is_closed());
+        subscribers.push(events.clone());
+    }
+
+    /// Queue `event` for the subscribers of `topic`.
+    /// Returns the number of subscribers it was queued for
+    pub fn publish(&self, topic: &str, event: Event) -> usize {
+        let mut serialized = Vec::new();
+        event.serialize(&mut serialized);
+        let mut topics = self.topics.lock().unwrap();
+        let subscribers = match topics.get_mut(topic) {
+            Some(subscribers) => subscribers,
+            None => return 0,
+        };
+        let limit = self.queue_limit;
+        let mut queued = 0;
+        subscribers.retain(|events| match events.offer(&serialized, limit) {
+            Offer::Queued => {
+                queued += 1;
+                true
+            }
+            Offer::Overflowed => {
+                debug!("Dropping a subscriber of {} falling behind", topic);
+                false
+            }
+            Offer::Closed => false,
+        });
+        if subscribers.is_empty() {
+            topics.remove(topic);
+        }
+        queued
+    }
+
+    /// Number of subscribers of `topic`, including the ones that went away since
+    /// the last event was published or subscribed to it
+    pub fn subscribers(&self, topic: &str) -> usize {
+        self.topics
+            .lock()
+            .unwrap()
+            .get(topic)
+            .map(Vec::len)
+            .unwrap_or(0)
+    }
+}
+impl Default for Broadcast {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closed_subscribers_pruned_on_subscribe() {
+        let broadcast = Broadcast::new();
+        let (gone, gone_response) = EventStream::response();
+        let (closed, _closed_response) = EventStream::response();
+        broadcast.subscribe("quiet", &gone);
+        broadcast.subscribe("quiet", &closed);
+        assert_eq!(broadcast.subscribers("quiet"), 2);
+        // its client went away
+        drop(gone_response);
+        closed.close();
+
+        // other topics are left to `publish`
+        let (other, _other_response) = EventStream::response();
+        broadcast.subscribe("busy", &other);
+        assert_eq!(broadcast.subscribers("quiet"), 2);
+
+        let (events, _response) = EventStream::response();
+        broadcast.subscribe("quiet", &events);
+        assert_eq!(broadcast.subscribers("quiet"), 1);
+        assert_eq!(broadcast.publish("quiet", Event::data("hi")), 1);
+    }
+}
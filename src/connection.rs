@@ -0,0 +1,878 @@
+use http;
+use mio::{Interest, Token};
+use std;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::time::Instant;
+
+use errors::{Error, ErrorKind};
+use h2::{self, H2};
+use http_stream::{self, HttpStreamReader};
+use pool::{Dispatch, Ticket};
+use sse::Watcher;
+use timer::{Phase, Timeouts};
+use transport::{ListenerIndex, Transport};
+#[cfg(unix)]
+use unix::PeerCredentials;
+use upgrade::{self, OnUpgrade, Upgraded};
+use websocket::{self, Session};
+use {
+    Body, ErrorHandler, ExpectHandler, Limits, Request, RequestHead, Response, ResponseWrapper,
+    Server, WebSocketHandler, MAX_PIPELINED_RESPONSES,
+};
+
+/// Slot for the response to a request read from a connection. Slots are queued
+/// in request order and stay empty while their handler runs on a worker thread
+struct PendingResponse {
+    seq: u64,
+    keep_alive: bool,
+    /// Version of the request, the response is sent with the same one
+    version: http::Version,
+    /// Whether the request is a `HEAD` request, the response body isn't sent
+    head: bool,
+    response: Option<ResponseWrapper>,
+}
+
+/// State of an accepted stream, carried across events by `Socket::Stream`
+pub(crate) struct Connection {
+    /// Unique for the lifetime of the server, unlike the `Token` which is reused
+    /// once the connection closes
+    pub id: u64,
+    pub stream: Transport,
+    reader: HttpStreamReader,
+    responses: VecDeque<PendingResponse>,
+    next_seq: u64,
+    /// Set once no further requests will be read from this connection
+    done_reading: bool,
+    /// Set when the server is shutting down, the connection closes after the
+    /// requests already received have been answered
+    draining: bool,
+    /// Bytes of the response at the front of the queue written so far
+    bytes_written: usize,
+    /// Renders the responses to requests that failed, see `reject`
+    error_handler: Option<ErrorHandler>,
+    /// Whether `HEAD` requests are handed to the handler as `GET` requests
+    head_as_get: bool,
+    /// Decides whether clients expecting a `100 Continue` may send their body
+    expect_handler: Option<ExpectHandler>,
+    /// What the connection is waiting on, `None` while a handler runs
+    phase: Option<Phase>,
+    /// When the current phase started, or writing last made progress
+    since: Instant,
+    /// When the current phase times out
+    deadline: Option<Instant>,
+    /// Set when responses were written since the deadline was last updated
+    wrote: bool,
+    limits: Limits,
+    /// Whether clients may switch to HTTP/2, see `Server::http2`
+    http2: bool,
+    /// Set once the connection switched to HTTP/2, the HTTP/1 state above is unused
+    h2: Option<Box<H2>>,
+    /// Accepts clients asking to switch to WebSocket
+    websocket_handler: Option<WebSocketHandler>,
+    /// Set once a WebSocket handshake was accepted. The connection switches once
+    /// the responses queued before it are written, received bytes go to it right away
+    websocket: Option<Box<Session>>,
+    /// Wakes the event loop when event stream responses have something new to write
+    watcher: Watcher,
+    /// Set once a `101 Switching Protocols` response was written, takes over the
+    /// connection when it's closed
+    upgrade: Option<upgrade::Callback>,
+    /// Sequence number of a request asking to switch protocols. What follows it may
+    /// belong to the new protocol, parsing pauses until its response is known
+    upgrade_requested: Option<u64>,
+    /// Which of the server's listeners accepted the connection, added to each request
+    listener: ListenerIndex,
+    /// Credentials of the process that connected, added to each request
+    #[cfg(unix)]
+    peer_credentials: Option<PeerCredentials>,
+}
+impl Connection {
+    pub fn new(
+        id: u64,
+        stream: Transport,
+        listener: ListenerIndex,
+        server: &Server,
+        watcher: Watcher,
+    ) -> Self {
+        #[cfg(unix)]
+        let peer_credentials = stream.peer_credentials();
+        Self {
+            id,
+            stream,
+            reader: HttpStreamReader::new(server.limits),
+            responses: VecDeque::new(),
+            next_seq: 0,
+            done_reading: false,
+            draining: false,
+            bytes_written: 0,
+            error_handler: server.error_handler.clone(),
+            head_as_get: server.head_as_get,
+            expect_handler: server.expect_handler.clone(),
+            phase: Some(Phase::Idle),
+            since: Instant::now(),
+            deadline: None,
+            wrote: false,
+            limits: server.limits,
+            http2: server.http2,
+            h2: None,
+            websocket_handler: server.websocket_handler.clone(),
+            websocket: None,
+            watcher,
+            upgrade: None,
+            upgrade_requested: None,
+            listener,
+            #[cfg(unix)]
+            peer_credentials,
+        }
+    }
+
+    /// Read everything that's available from the stream. Bytes that arrive while
+    /// responses are still being written are kept in the reader's buffer and belong
+    /// to the next request on this connection. Reading pauses once more than the
+    /// request size limits allow is buffered, until the buffered requests are served.
+    /// Returns `true` if the stream has been closed
+    pub fn read(&mut self, token: Token) -> bool {
+        let mut buf = [0; 256];
+        while self.wants_more() {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    // the stream has ended for real
+                    return true;
+                }
+                Ok(n) => {
+                    if let Some(ref mut ws) = self.websocket {
+                        ws.receive_chunk(&buf[..n]);
+                    } else if let Some(ref mut h2) = self.h2 {
+                        h2.receive_chunk(&buf[..n]);
+                    } else {
+                        self.reader.receive_chunk(&buf[..n]);
+                    }
+                    debug!("{:?} - Read {} bytes", token, n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return false,
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => return true,
+                Err(e) => {
+                    error!(
+                        "{:?} - Encountered error while reading from socket: {:?}",
+                        token, e
+                    );
+                    // let this socket die
+                    return true;
+                }
+            }
+        }
+        debug!("{:?} - Read buffer full", token);
+        false
+    }
+
+    fn wants_more(&self) -> bool {
+        if let Some(ref ws) = self.websocket {
+            ws.wants_more()
+        } else if let Some(ref h2) = self.h2 {
+            h2.wants_more()
+        } else {
+            self.reader.wants_more()
+        }
+    }
+
+    /// Serve requests on this connection until we either run out of buffered bytes
+    /// or can't finish writing the queued responses yet. Pipelined requests are all
+    /// dispatched up front and their responses are written back in the order the
+    /// requests were received.
+    /// Returns `true` once the connection should be closed
+    pub fn serve<D: Dispatch>(&mut self, token: Token, dispatch: &D) -> bool {
+        let mut close = false;
+        // output the transport held back, e.g. the rest of a TLS handshake
+        if self.stream.wants_write() {
+            match self.stream.flush() {
+                Err(ref e) if e.kind() != io::ErrorKind::WouldBlock => {
+                    error!("{:?} - Encountered error while flushing: {:?}", token, e);
+                    return true;
+                }
+                _ => {}
+            }
+        }
+        if self.h2.is_some() {
+            return self.serve_h2(token, dispatch);
+        }
+        if self.switched_websocket().is_some() {
+            return self.serve_websocket(token);
+        }
+        loop {
+            // Try parsing requests from the buffered bytes.
+            // `try_build_request` will return `None` until a request is parsed and
+            // its body is done being read
+            let mut parsed = false;
+            while !self.done_reading
+                && self.upgrade_requested.is_none()
+                && self.responses.len() < MAX_PIPELINED_RESPONSES
+            {
+                let seq = self.next_seq;
+                if self.http2 && seq == 0 {
+                    // an HTTP/2 client with prior knowledge starts with the preface,
+                    // which doesn't parse as an HTTP/1 request
+                    let buf = &self.reader.read_buf;
+                    let len = buf.len().min(h2::PREFACE.len());
+                    if buf[..len] == h2::PREFACE[..len] {
+                        if len < h2::PREFACE.len() {
+                            break;
+                        }
+                        debug!("{:?} - Switching to HTTP/2", token);
+                        let received = std::mem::take(&mut self.reader.read_buf);
+                        self.h2 = Some(Box::new(H2::new(
+                            received,
+                            self.limits,
+                            self.error_handler.clone(),
+                            self.head_as_get,
+                            self.watcher.clone(),
+                        )));
+                        return self.serve_h2(token, dispatch);
+                    }
+                }
+                match self.reader.try_build_request() {
+                    Ok(Some(req)) => {
+                        let req = {
+                            let mut req = req;
+                            req.extensions_mut().insert(self.listener);
+                            #[cfg(feature = "tls")]
+                            {
+                                if let Some(info) = self.stream.tls_info() {
+                                    req.extensions_mut().insert(info);
+                                }
+                            }
+                            #[cfg(unix)]
+                            {
+                                if let Some(peer) = self.peer_credentials {
+                                    req.extensions_mut().insert(peer);
+                                }
+                            }
+                            req
+                        };
+                        if self.responses.is_empty() && self.wants_h2c(&req) {
+                            self.upgrade_h2c(req);
+                            return self.serve_h2(token, dispatch);
+                        }
+                        let req = match self.upgrade_websocket(token, req) {
+                            Some(req) => req,
+                            None => {
+                                parsed = true;
+                                continue;
+                            }
+                        };
+                        // The head-only request (RequestHead) is converted into
+                        // a public `Request` and the `HttpStreamReader`s `read_buf`,
+                        // now holding only this request, is swapped into the new
+                        // `Request`s body before it's handed to the handler
+                        debug!("Begin processing the response for token {:?}", token);
+                        let upgrade = req.headers().contains_key(http::header::UPGRADE)
+                            && http_stream::has_connection_option(&req, "upgrade");
+                        let (mut parts, _) = req.into_parts();
+                        let head = parts.method == http::Method::HEAD;
+                        if head && self.head_as_get {
+                            parts.method = http::Method::GET;
+                        }
+                        let mut body = vec![];
+                        std::mem::swap(&mut body, &mut self.reader.read_buf);
+                        let request = Request {
+                            inner: http::Request::from_parts(parts, body),
+                            body_start: self.reader.headers_length,
+                        };
+                        let keep_alive = self.reader.keep_alive && !self.draining;
+                        self.reader.reset();
+                        if !keep_alive {
+                            // nothing after this request will be answered
+                            self.done_reading = true;
+                        }
+
+                        let version = request.version();
+                        self.next_seq += 1;
+                        self.responses.push_back(PendingResponse {
+                            seq,
+                            keep_alive,
+                            version,
+                            head,
+                            response: None,
+                        });
+                        if upgrade {
+                            self.upgrade_requested = Some(seq);
+                        }
+                        let ticket = Ticket {
+                            token,
+                            conn_id: self.id,
+                            seq,
+                        };
+                        if let Some(resp) = dispatch.dispatch(ticket, request) {
+                            self.complete(seq, resp);
+                        }
+                    }
+                    Ok(None) => {
+                        self.expect_continue(token);
+                        break;
+                    }
+                    Err(e) => {
+                        error!("{:?} - Encountered error while parsing: {}", token, e);
+                        self.reject(&e);
+                    }
+                }
+                parsed = true;
+            }
+
+            // Write the ready responses, oldest first, stopping at the first one that's
+            // still being handled. We don't wait for a writable event since the socket
+            // is usually writable already, `WouldBlock` tells us otherwise
+            while self.front_ready() {
+                let mut pending = self.responses.pop_front().unwrap();
+                let written = self.bytes_written;
+                let done = {
+                    let resp = pending.response.as_mut().unwrap();
+                    debug!("Response ready to be written for token {:?}", token);
+                    resp.write_to(&mut self.stream, &mut self.bytes_written)
+                };
+                if self.bytes_written != written {
+                    self.wrote = true;
+                }
+                match done {
+                    Ok(true) => {
+                        debug!("{:?} - Done writing {} bytes", token, self.bytes_written);
+                        self.bytes_written = 0;
+                        let upgrade = pending
+                            .response
+                            .as_mut()
+                            .and_then(|r| r.extensions_mut().remove::<OnUpgrade>())
+                            .and_then(OnUpgrade::take);
+                        if upgrade.is_some() {
+                            debug!(
+                                "{:?} - Switched protocols, handing the connection over",
+                                token
+                            );
+                            self.upgrade = upgrade;
+                            close = true;
+                            break;
+                        }
+                        if !pending.response.map(|r| r.keep_alive).unwrap_or(false) {
+                            close = true;
+                            break;
+                        }
+                    }
+                    Ok(false) => {
+                        debug!("Response not writeable for token {:?}", token);
+                        self.responses.push_front(pending);
+                        break;
+                    }
+                    Err(e) => {
+                        error!(
+                            "{:?} - Encountered error while writing to socket: {:?}",
+                            token, e
+                        );
+                        // let this socket die
+                        close = true;
+                        break;
+                    }
+                }
+            }
+
+            // Keep going while the queue was flushed and more requests may be
+            // buffered behind the ones we just answered
+            if close || !self.responses.is_empty() {
+                return close;
+            }
+            if self.websocket.is_some() {
+                return self.serve_websocket(token);
+            }
+            if !parsed {
+                // bytes the transport took off the socket won't make it readable
+                // again, read them now that there's room for them
+                if self.done_reading
+                    || !self.reader.wants_more()
+                    || !self.stream.has_buffered_input()
+                {
+                    return close;
+                }
+                if self.read(token) {
+                    return true;
+                }
+            }
+        }
+    }
+
+    /// Serve the requests of an HTTP/2 connection, see `serve`
+    fn serve_h2<D: Dispatch>(&mut self, token: Token, dispatch: &D) -> bool {
+        #[cfg(feature = "tls")]
+        let tls = self.stream.tls_info();
+        #[cfg(unix)]
+        let peer = self.peer_credentials;
+        let listener = self.listener;
+        loop {
+            let h2 = self.h2.as_mut().unwrap();
+            let close = h2.serve(token, self.id, dispatch, &mut self.stream, |parts| {
+                parts.extensions.insert(listener);
+                #[cfg(feature = "tls")]
+                {
+                    if let Some(ref info) = tls {
+                        parts.extensions.insert(info.clone());
+                    }
+                }
+                #[cfg(unix)]
+                {
+                    if let Some(peer) = peer {
+                        parts.extensions.insert(peer);
+                    }
+                }
+            });
+            if h2.wrote {
+                h2.wrote = false;
+                self.wrote = true;
+            }
+            // same as with HTTP/1, bytes the transport holds won't make the socket
+            // readable again
+            if close || !h2.wants_more() || !self.stream.has_buffered_input() {
+                return close;
+            }
+            if self.read(token) {
+                return true;
+            }
+        }
+    }
+
+    /// Serve a connection switched to WebSocket, see `serve`
+    fn serve_websocket(&mut self, token: Token) -> bool {
+        loop {
+            let ws = self.websocket.as_mut().unwrap();
+            let close = ws.serve(token, &mut self.stream);
+            if ws.wrote {
+                ws.wrote = false;
+                self.wrote = true;
+            }
+            if close || !ws.wants_more() || !self.stream.has_buffered_input() {
+                return close;
+            }
+            if self.read(token) {
+                return true;
+            }
+        }
+    }
+
+    /// Switch to WebSocket after `request` if it asks to and the server's handler
+    /// accepts it, a malformed handshake is answered with an error response.
+    /// Returns the request when it's left to the handler
+    fn upgrade_websocket(&mut self, token: Token, request: RequestHead) -> Option<RequestHead> {
+        let accept = match self.websocket_handler {
+            Some(ref accept) if !self.draining && websocket::is_upgrade(&request) => accept.clone(),
+            _ => return Some(request),
+        };
+        // the application only hears about handshakes that can succeed
+        let response = match websocket::handshake(&request) {
+            Ok(response) => response,
+            Err(e) => {
+                error!("{:?} - Invalid WebSocket handshake: {}", token, e);
+                let mut response = error_response(&self.error_handler, &e);
+                if let ErrorKind::UnsupportedWebSocketVersion(_) = *e.kind() {
+                    response.headers_mut().insert(
+                        http::header::SEC_WEBSOCKET_VERSION,
+                        http::HeaderValue::from_static(websocket::VERSION),
+                    );
+                }
+                self.close_with(response);
+                return None;
+            }
+        };
+        let callback = match accept(&request) {
+            Some(callback) => callback,
+            None => return Some(request),
+        };
+        debug!("{:?} - Switching to WebSocket", token);
+        let mut ws = Session::new(
+            response,
+            callback,
+            self.limits.max_body_bytes,
+            self.watcher.clone(),
+        );
+        // what followed the request is the start of the WebSocket connection
+        self.reader.read_buf.clear();
+        self.reader.reset();
+        ws.receive_chunk(&std::mem::take(&mut self.reader.read_buf));
+        self.websocket = Some(Box::new(ws));
+        self.done_reading = true;
+        None
+    }
+
+    /// Whether `request` asks to switch to HTTP/2, which is only possible over
+    /// plain connections
+    fn wants_h2c(&self, request: &RequestHead) -> bool {
+        self.http2
+            && !self.stream.is_encrypted()
+            && request.version() == http::Version::HTTP_11
+            && request
+                .headers()
+                .get_all(http::header::UPGRADE)
+                .iter()
+                .filter_map(|val| val.to_str().ok())
+                .flat_map(|val| val.split(','))
+                .any(|protocol| protocol.trim().eq_ignore_ascii_case("h2c"))
+            && http_stream::has_connection_option(request, "http2-settings")
+            && request.headers().get_all("http2-settings").iter().count() == 1
+    }
+
+    /// Switch to HTTP/2 after `request`, which is answered as its first stream
+    fn upgrade_h2c(&mut self, request: RequestHead) {
+        let settings = request
+            .headers()
+            .get("http2-settings")
+            .and_then(|val| base64url_decode(val.as_bytes()))
+            .unwrap_or_default();
+        let (parts, _) = request.into_parts();
+        let mut body = std::mem::take(&mut self.reader.read_buf);
+        body.drain(..self.reader.headers_length);
+        // the bytes following the request are the start of the HTTP/2 connection
+        self.reader.reset();
+        let received = std::mem::take(&mut self.reader.read_buf);
+        let h2 = H2::new(
+            received,
+            self.limits,
+            self.error_handler.clone(),
+            self.head_as_get,
+            self.watcher.clone(),
+        );
+        self.h2 = Some(Box::new(h2.upgrade(&settings, parts, body)));
+    }
+
+    /// Answer the request being received with an error and stop reading, we
+    /// can't tell where the next request would start so the connection can't
+    /// be reused. The response is rendered by the server's error handler if
+    /// there's one, otherwise it's the status' reason phrase
+    pub fn reject(&mut self, error: &Error) {
+        let response = error_response(&self.error_handler, error);
+        self.close_with(response);
+    }
+
+    /// Answer the request being received with `response` and stop reading. The
+    /// response is in the version of the request, or HTTP/1.1 if it couldn't be
+    /// parsed that far
+    fn close_with(&mut self, response: Response<Body>) {
+        let seq = self.next_seq;
+        self.done_reading = true;
+        self.next_seq += 1;
+        self.responses.push_back(PendingResponse {
+            seq,
+            keep_alive: false,
+            version: self.reader.version().unwrap_or(http::Version::HTTP_11),
+            head: false,
+            response: None,
+        });
+        self.complete(seq, response);
+    }
+
+    /// Answer a client waiting to send the body of the request being received,
+    /// with a `100 Continue` or the response of the server's expect handler
+    /// turning the body down
+    fn expect_continue(&mut self, token: Token) {
+        let rejection = match self.reader.take_expect_continue() {
+            Some(head) => self
+                .expect_handler
+                .as_ref()
+                .and_then(|handler| handler(head)),
+            None => return,
+        };
+        match rejection {
+            Some(response) => {
+                debug!("{:?} - Request body turned down", token);
+                self.close_with(response);
+            }
+            None => {
+                debug!("{:?} - Sending 100 Continue", token);
+                // queued like any response so it's written after the ones before it
+                let seq = self.next_seq;
+                self.next_seq += 1;
+                self.responses.push_back(PendingResponse {
+                    seq,
+                    keep_alive: true,
+                    version: http::Version::HTTP_11,
+                    head: true,
+                    response: Some(ResponseWrapper::informational(http::StatusCode::CONTINUE)),
+                });
+            }
+        }
+    }
+
+    /// Fill in the response to the request numbered `seq`, once its handler is done
+    pub fn complete(&mut self, seq: u64, response: Response<Body>) {
+        if let Some(ref mut h2) = self.h2 {
+            h2.complete(seq, response);
+            return;
+        }
+        if self.upgrade_requested == Some(seq) {
+            self.upgrade_requested = None;
+        }
+        let pending = match self.responses.iter_mut().find(|p| p.seq == seq) {
+            Some(pending) => pending,
+            None => return,
+        };
+        if let Some(events) = response.body().events() {
+            events.watch(self.watcher.clone());
+            if self.draining {
+                events.close();
+            }
+        }
+        let mut resp = ResponseWrapper::new(response);
+        resp.serialize_headers(pending.keep_alive, pending.version, pending.head);
+        if resp.extensions().get::<OnUpgrade>().is_some() {
+            if resp.status() == http::StatusCode::SWITCHING_PROTOCOLS {
+                // what follows the request belongs to the new protocol
+                self.done_reading = true;
+            } else {
+                resp.extensions_mut().remove::<OnUpgrade>();
+            }
+        }
+        if !resp.keep_alive {
+            // the handler asked to close the connection after this response
+            self.done_reading = true;
+        }
+        pending.response = Some(resp);
+    }
+
+    /// Close this connection once the requests already (partially) received have
+    /// been answered, for a graceful shutdown.
+    /// Returns `true` if the connection is idle and can be closed right away
+    pub fn drain(&mut self) -> bool {
+        self.draining = true;
+        if let Some(ref mut h2) = self.h2 {
+            return h2.drain();
+        }
+        if let Some(ref mut ws) = self.websocket {
+            if self.responses.is_empty() {
+                ws.drain();
+                return false;
+            }
+        }
+        // event streams would never end on their own
+        for pending in &self.responses {
+            if let Some(events) = pending.response.as_ref().and_then(|r| r.body().events()) {
+                events.close();
+            }
+        }
+        match self.responses.back_mut() {
+            Some(last) => {
+                // the response to the last request received closes the connection
+                last.keep_alive = false;
+                if let Some(ref mut resp) = last.response {
+                    resp.keep_alive = false;
+                }
+                self.done_reading = true;
+                false
+            }
+            None => !self.reader.has_partial_request(),
+        }
+    }
+
+    /// What the connection is currently waiting on, `None` while waiting on a handler
+    fn current_phase(&self) -> Option<Phase> {
+        if let Some(ref h2) = self.h2 {
+            h2.phase()
+        } else if let Some(ws) = self.switched_websocket() {
+            // quiet clients are pinged once the idle timeout expires
+            if ws.wants_write() {
+                Some(Phase::Write)
+            } else {
+                Some(Phase::Idle)
+            }
+        } else if self.front_waiting() {
+            Some(Phase::Events)
+        } else if self.front_ready() {
+            Some(Phase::Write)
+        } else if !self.responses.is_empty() {
+            None
+        } else if self.reader.headers_complete() {
+            Some(Phase::Body)
+        } else if self.reader.has_partial_request() {
+            Some(Phase::Header)
+        } else {
+            Some(Phase::Idle)
+        }
+    }
+
+    /// Restart the timeout when the connection moved on to another phase, or made
+    /// progress writing. Returns the new deadline when it changed
+    pub fn update_deadline(&mut self, timeouts: &Timeouts) -> Option<Instant> {
+        let phase = self.current_phase();
+        let writing = phase == Some(Phase::Write) || phase == Some(Phase::Events);
+        if phase != self.phase || (self.wrote && writing) {
+            self.phase = phase;
+            self.since = Instant::now();
+        }
+        self.wrote = false;
+        let deadline = self
+            .phase
+            .and_then(|phase| timeouts.get(phase))
+            .map(|timeout| self.since + timeout);
+        if deadline == self.deadline {
+            return None;
+        }
+        self.deadline = deadline;
+        deadline
+    }
+
+    /// Whether the current phase has timed out by `now`
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.deadline.map(|d| d <= now).unwrap_or(false)
+    }
+
+    /// Handle the current phase timing out. A request that's taking too long to
+    /// be received is answered with a `408`, otherwise the connection is dropped.
+    /// Returns `true` if the connection should be closed right away
+    pub fn expire(&mut self, token: Token) -> bool {
+        self.deadline = None;
+        match self.phase {
+            Some(Phase::Events) => {
+                debug!("{:?} - Keeping event streams alive", token);
+                if let Some(ref h2) = self.h2 {
+                    h2.keep_alive();
+                } else if let Some(events) = self
+                    .responses
+                    .front()
+                    .and_then(|p| p.response.as_ref())
+                    .and_then(|r| r.body().events())
+                {
+                    events.keep_alive();
+                }
+                self.since = Instant::now();
+                false
+            }
+            _ if self.h2.is_some() => {
+                debug!("{:?} - Timed out in {:?}", token, self.phase);
+                true
+            }
+            Some(Phase::Idle) if self.switched_websocket().is_some() => {
+                let ws = self.websocket.as_mut().unwrap();
+                if ws.ping() {
+                    debug!("{:?} - Pinging idle WebSocket client", token);
+                    self.since = Instant::now();
+                    false
+                } else {
+                    debug!("{:?} - WebSocket client stopped answering", token);
+                    true
+                }
+            }
+            Some(Phase::Header) | Some(Phase::Body) => {
+                debug!("{:?} - Timed out receiving a request", token);
+                self.reject(
+                    &ErrorKind::RequestTimeout("Timed out receiving a request".into()).into(),
+                );
+                false
+            }
+            phase => {
+                debug!("{:?} - Timed out in {:?}", token, phase);
+                true
+            }
+        }
+    }
+
+    /// Interest to register the stream with. Only ask for writable events
+    /// while there's something to write
+    pub fn interest(&self) -> Interest {
+        let wants_write = if let Some(ref h2) = self.h2 {
+            h2.wants_write()
+        } else if let Some(ws) = self.switched_websocket() {
+            ws.wants_write()
+        } else {
+            self.front_ready() && !self.front_waiting()
+        };
+        if wants_write || self.stream.wants_write() {
+            Interest::READABLE | Interest::WRITABLE
+        } else {
+            Interest::READABLE
+        }
+    }
+
+    /// The upgrade callback and what it takes over, once a `101 Switching Protocols`
+    /// response was written. Only plain TCP connections can be handed over
+    pub fn into_upgraded(self) -> Option<(upgrade::Callback, Upgraded)> {
+        let callback = self.upgrade?;
+        match self.stream {
+            Transport::Plain(stream) => Some((
+                callback,
+                Upgraded {
+                    stream,
+                    buffered: self.reader.read_buf,
+                },
+            )),
+            #[cfg(unix)]
+            Transport::Unix(_) => {
+                warn!("Can't hand a Unix socket connection over, closing it");
+                None
+            }
+            #[cfg(feature = "tls")]
+            Transport::Tls(_) => {
+                warn!("Can't hand a TLS connection over, closing it");
+                None
+            }
+        }
+    }
+
+    /// The WebSocket side of the connection, once it switched
+    fn switched_websocket(&self) -> Option<&Session> {
+        match self.websocket {
+            Some(ref ws) if self.responses.is_empty() => Some(ws),
+            _ => None,
+        }
+    }
+
+    /// Whether the response at the front of the queue is ready to be written
+    fn front_ready(&self) -> bool {
+        self.responses
+            .front()
+            .map(|p| p.response.is_some())
+            .unwrap_or(false)
+    }
+
+    /// Whether the response at the front of the queue is an event stream waiting
+    /// for its next events
+    fn front_waiting(&self) -> bool {
+        self.responses
+            .front()
+            .and_then(|p| p.response.as_ref())
+            .map(|r| r.waiting)
+            .unwrap_or(false)
+    }
+}
+
+/// The response to a request that failed with `error`, rendered by the server's
+/// error handler if there's one, otherwise it's the status' reason phrase
+pub(crate) fn error_response(handler: &Option<ErrorHandler>, error: &Error) -> Response<Body> {
+    match *handler {
+        Some(ref handler) => handler(error),
+        None => {
+            let status = error.kind().status();
+            let reason = status.canonical_reason().unwrap_or("error");
+            Response::builder()
+                .status(status)
+                .body(reason.to_lowercase().into())
+                .unwrap()
+        }
+    }
+}
+
+/// Decode the unpadded base64url of an `HTTP2-Settings` header
+fn base64url_decode(encoded: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for &c in encoded {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            b'=' => break,
+            _ => return None,
+        };
+        bits = bits << 6 | u32::from(value);
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+        }
+    }
+    Some(decoded)
+}
@@ -24,5 +24,59 @@ error_chain! {
             description("Request Body Too Large")
             display("RequestBodyTooLarge: {}", s)
         }
+        UriTooLong(s: String) {
+            description("URI Too Long")
+            display("UriTooLong: {}", s)
+        }
+        UnsupportedVersion(s: String) {
+            description("Unsupported HTTP Version")
+            display("UnsupportedVersion: {}", s)
+        }
+        UnsupportedTransferCoding(s: String) {
+            description("Unsupported Transfer Coding")
+            display("UnsupportedTransferCoding: {}", s)
+        }
+        ExpectationFailed(s: String) {
+            description("Expectation Failed")
+            display("ExpectationFailed: {}", s)
+        }
+        RequestTimeout(s: String) {
+            description("Request Timeout")
+            display("RequestTimeout: {}", s)
+        }
+        TlsConfig(s: String) {
+            description("Invalid TLS Configuration")
+            display("TlsConfig: {}", s)
+        }
+        TooManyConnections(s: String) {
+            description("Too Many Connections")
+            display("TooManyConnections: {}", s)
+        }
+        UnsupportedWebSocketVersion(s: String) {
+            description("Unsupported WebSocket Version")
+            display("UnsupportedWebSocketVersion: {}", s)
+        }
+    }
+}
+
+impl ErrorKind {
+    /// Status code of the response sent back when a request fails with this error
+    pub fn status(&self) -> http::StatusCode {
+        use http::StatusCode;
+        match *self {
+            ErrorKind::MalformedHttpRequest(_) | ErrorKind::IncompleteHttpRequest(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            ErrorKind::RequestHeadersTooLarge(_) => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            ErrorKind::RequestBodyTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorKind::UriTooLong(_) => StatusCode::URI_TOO_LONG,
+            ErrorKind::UnsupportedVersion(_) => StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+            ErrorKind::UnsupportedTransferCoding(_) => StatusCode::NOT_IMPLEMENTED,
+            ErrorKind::ExpectationFailed(_) => StatusCode::EXPECTATION_FAILED,
+            ErrorKind::RequestTimeout(_) => StatusCode::REQUEST_TIMEOUT,
+            ErrorKind::TooManyConnections(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorKind::UnsupportedWebSocketVersion(_) => StatusCode::UPGRADE_REQUIRED,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
     }
 }
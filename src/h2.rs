@@ -0,0 +1,1172 @@
+//! HTTP/2 (RFC 7540) on a connection that started out as, or was upgraded from,
+//! HTTP/1. Requests are read off their streams and handed to the handler through
+//! the same `Dispatch` as HTTP/1 requests, with the stream id as their `seq`.
+//!
+//! Request bodies are buffered until they're complete. Each stream's flow control
+//! window fits a body of `Limits::max_body_bytes`, the connection's window only
+//! grows back as bodies are handed to the handler, so a connection never buffers
+//! much more than one such body at once: concurrent uploads share it, one left
+//! stalled meanwhile times out. Server push isn't supported.
+use http;
+use mio::Token;
+use std;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use connection::error_response;
+use errors::*;
+use hpack;
+use http_stream::Trailers;
+use pool::{Dispatch, Ticket};
+use sse::{Receiver, Watcher};
+use timer::Phase;
+use transport::Transport;
+use {header, Body, ErrorHandler, Limits, Request, Response, STREAM_WRITE_BUDGET};
+
+/// Sent by clients before anything else
+pub(crate) const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_HEADER_LEN: usize = 9;
+/// Largest frame we accept, the protocol's default `SETTINGS_MAX_FRAME_SIZE`
+const MAX_FRAME_SIZE: usize = 16_384;
+/// Streams a client may have open at once
+const MAX_CONCURRENT_STREAMS: usize = 100;
+const DEFAULT_WINDOW: i64 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+/// Frames are only generated while less than this is waiting to be written
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+// frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// frame flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// settings
+const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+/// Error code of a `RST_STREAM` or `GOAWAY` frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Reason(u32);
+impl Reason {
+    const NO_ERROR: Reason = Reason(0x0);
+    const PROTOCOL_ERROR: Reason = Reason(0x1);
+    const INTERNAL_ERROR: Reason = Reason(0x2);
+    const FLOW_CONTROL_ERROR: Reason = Reason(0x3);
+    const STREAM_CLOSED: Reason = Reason(0x5);
+    const FRAME_SIZE_ERROR: Reason = Reason(0x6);
+    const REFUSED_STREAM: Reason = Reason(0x7);
+    const COMPRESSION_ERROR: Reason = Reason(0x9);
+    const ENHANCE_YOUR_CALM: Reason = Reason(0xb);
+}
+
+/// A connection error, answered with a `GOAWAY` before closing the connection
+type ConnResult<T> = std::result::Result<T, Reason>;
+
+/// Response headers that only make sense for HTTP/1 connections
+const CONNECTION_HEADERS: &[header::HeaderName] = &[
+    header::CONNECTION,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+/// A request/response exchange
+struct Stream {
+    /// Head of the request, until it's dispatched
+    head: Option<http::request::Parts>,
+    body: Vec<u8>,
+    trailers: Option<http::HeaderMap>,
+    /// Set once the client is done sending the request
+    recv_closed: bool,
+    /// Why the request is answered with an error response instead of the handler's.
+    /// Further request data is discarded
+    rejected: Option<Error>,
+    /// Whether the response to a `HEAD` request is sent without its body
+    head_request: bool,
+    response: Option<Outgoing>,
+    send_window: i64,
+    /// Bytes the client may still send on the stream
+    recv_window: i64,
+}
+impl Stream {
+    fn new(send_window: i64, recv_window: i64) -> Self {
+        Self {
+            head: None,
+            body: Vec::new(),
+            trailers: None,
+            recv_closed: false,
+            rejected: None,
+            head_request: false,
+            response: None,
+            send_window,
+            recv_window,
+        }
+    }
+}
+
+/// A response being sent on a stream
+struct Outgoing {
+    /// Encoded headers, until they're sent
+    header_block: Option<Vec<u8>>,
+    body: Body,
+    /// Bytes of a full body sent so far, or of the current chunk of a streamed one
+    offset: usize,
+    chunk: Vec<u8>,
+    /// Set once the whole body has been produced, only the end of the stream is
+    /// left to send after what's buffered
+    exhausted: bool,
+}
+impl Outgoing {
+    /// Body bytes that are ready to be sent
+    fn pending(&self) -> &[u8] {
+        if self.body.is_stream() {
+            &self.chunk[self.offset..]
+        } else {
+            &self.body.bytes()[self.offset..]
+        }
+    }
+
+    /// Whether the next frame of this response can be sent with `window` bytes of
+    /// flow control window
+    fn sendable(&self, window: i64) -> bool {
+        self.header_block.is_some()
+            || (self.exhausted && self.pending().is_empty())
+            || (window > 0 && !self.is_waiting())
+    }
+
+    /// Whether this is an event stream with nothing to send until more events are sent
+    fn is_waiting(&self) -> bool {
+        self.header_block.is_none()
+            && self.pending().is_empty()
+            && !self.exhausted
+            && self.body.is_waiting()
+    }
+}
+
+/// The HTTP/2 state of a connection
+pub(crate) struct H2 {
+    /// Received bytes that don't make a complete frame yet
+    read_buf: Vec<u8>,
+    /// Frames waiting to be written, from `written` on
+    write_buf: Vec<u8>,
+    written: usize,
+    /// Set when bytes were written since the last time it was cleared, see
+    /// `Connection::update_deadline`
+    pub wrote: bool,
+    preface_received: bool,
+    settings_received: bool,
+    decoder: hpack::Decoder,
+    streams: BTreeMap<u32, Stream>,
+    /// Streams whose request has been fully received, in order
+    ready: Vec<u32>,
+    /// Highest stream id opened by the client
+    last_stream_id: u32,
+    /// Header block spread over `CONTINUATION` frames: stream id, whether it
+    /// ends the stream, and the fragments so far
+    continuation: Option<(u32, bool, Vec<u8>)>,
+    send_window: i64,
+    /// Bytes the client may still send on the connection
+    recv_window: i64,
+    /// Our `SETTINGS_INITIAL_WINDOW_SIZE`, also the most request body bytes the
+    /// connection buffers, counting those the client may still send
+    stream_window: i64,
+    /// Peer settings
+    initial_window: i64,
+    max_frame_size: usize,
+    /// Set once either side sent a `GOAWAY`, no new streams are accepted
+    going_away: bool,
+    /// Set after a connection error, the connection closes once the `GOAWAY`
+    /// is written
+    failed: bool,
+    limits: Limits,
+    error_handler: Option<ErrorHandler>,
+    head_as_get: bool,
+    /// Wakes the event loop when event stream responses have something new to write
+    watcher: Watcher,
+}
+impl H2 {
+    /// The HTTP/2 side of a connection whose client sent the connection preface,
+    /// which is left in the buffered `received` bytes
+    pub fn new(
+        received: Vec<u8>,
+        limits: Limits,
+        error_handler: Option<ErrorHandler>,
+        head_as_get: bool,
+        watcher: Watcher,
+    ) -> Self {
+        // room for a body one byte over the limit, so that it's found to be too large
+        let stream_window = (limits.max_body_bytes.min(MAX_WINDOW as usize) as i64 + 1)
+            .clamp(DEFAULT_WINDOW, MAX_WINDOW);
+        let mut h2 = Self {
+            read_buf: received,
+            write_buf: Vec::new(),
+            written: 0,
+            wrote: false,
+            preface_received: false,
+            settings_received: false,
+            decoder: hpack::Decoder::new(),
+            streams: BTreeMap::new(),
+            ready: Vec::new(),
+            last_stream_id: 0,
+            continuation: None,
+            send_window: DEFAULT_WINDOW,
+            recv_window: DEFAULT_WINDOW,
+            stream_window,
+            initial_window: DEFAULT_WINDOW,
+            max_frame_size: MAX_FRAME_SIZE,
+            going_away: false,
+            failed: false,
+            limits,
+            error_handler,
+            head_as_get,
+            watcher,
+        };
+        h2.send_settings();
+        h2.replenish();
+        h2
+    }
+
+    /// Turn a new HTTP/2 side, created with what followed `request` as received
+    /// bytes, into that of a connection upgraded by `request`. The request becomes
+    /// stream 1 and is answered once the client sent its preface. `settings` are
+    /// the decoded `HTTP2-Settings` of the request
+    pub fn upgrade(
+        mut self,
+        settings: &[u8],
+        request: http::request::Parts,
+        body: Vec<u8>,
+    ) -> Self {
+        // the switch is announced before anything else
+        let mut write_buf =
+            b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n"
+                .to_vec();
+        write_buf.extend_from_slice(&self.write_buf);
+        self.write_buf = write_buf;
+        if self.apply_settings(settings).is_err() {
+            self.go_away(Reason::PROTOCOL_ERROR);
+        }
+        let mut stream = Stream::new(self.initial_window, 0);
+        stream.head_request = request.method == http::Method::HEAD;
+        stream.head = Some(request);
+        stream.body = body;
+        stream.recv_closed = true;
+        self.streams.insert(1, stream);
+        self.ready.push(1);
+        self.last_stream_id = 1;
+        self
+    }
+
+    /// Whether more bytes should be read, reading pauses while a few frames are
+    /// buffered already
+    pub fn wants_more(&self) -> bool {
+        self.read_buf.len() < 4 * (MAX_FRAME_SIZE + FRAME_HEADER_LEN)
+    }
+
+    pub fn receive_chunk(&mut self, chunk: &[u8]) {
+        self.read_buf.extend_from_slice(chunk);
+    }
+
+    /// Handle the frames received so far, dispatch the requests they completed and
+    /// write as much as `stream` takes. `extend` adds the connection's details to
+    /// each request's extensions.
+    /// Returns `true` once the connection should be closed
+    pub fn serve<D, F>(
+        &mut self,
+        token: Token,
+        conn_id: u64,
+        dispatch: &D,
+        stream: &mut Transport,
+        extend: F,
+    ) -> bool
+    where
+        D: Dispatch,
+        F: Fn(&mut http::request::Parts),
+    {
+        if !self.failed {
+            if let Err(reason) = self.process() {
+                error!("{:?} - HTTP/2 connection error: {:?}", token, reason);
+                self.go_away(reason);
+            }
+        }
+        for id in std::mem::take(&mut self.ready) {
+            let ticket = Ticket {
+                token,
+                conn_id,
+                seq: u64::from(id),
+            };
+            if let Some(response) = self.dispatch(id, ticket, dispatch, &extend) {
+                self.complete(u64::from(id), response);
+            }
+        }
+        if !self.failed {
+            // the bodies handed over or dropped make room for more
+            self.replenish();
+        }
+
+        let mut budget = STREAM_WRITE_BUDGET;
+        loop {
+            let produced = self.produce(&mut budget);
+            match self.write(stream) {
+                Ok(true) => {}
+                // blocked, we'll get a writable event
+                Ok(false) => break,
+                Err(e) => {
+                    error!(
+                        "{:?} - Encountered error while writing to socket: {:?}",
+                        token, e
+                    );
+                    return true;
+                }
+            }
+            if !produced || budget == 0 {
+                break;
+            }
+        }
+        self.write_buf.is_empty() && (self.failed || (self.going_away && self.streams.is_empty()))
+    }
+
+    /// Fill in the response to the stream numbered `id`, once its handler is done
+    pub fn complete(&mut self, id: u64, mut response: Response<Body>) {
+        let stream = match self.streams.get_mut(&(id as u32)) {
+            Some(stream) => stream,
+            // reset by the client in the meantime
+            None => return,
+        };
+        let status = response.status();
+        let body_len = response.body().content_length();
+        let bodiless = status.is_informational()
+            || status == http::StatusCode::NO_CONTENT
+            || status == http::StatusCode::NOT_MODIFIED;
+        // the stream ends with the headers when there's no body to send
+        let head = stream.head_request || bodiless;
+        {
+            let hdrs = response.headers_mut();
+            for name in CONNECTION_HEADERS {
+                hdrs.remove(name);
+            }
+            hdrs.remove("keep-alive");
+            hdrs.remove("proxy-connection");
+            hdrs.insert(
+                header::SERVER,
+                header::HeaderValue::from_static("mini-http (rust)"),
+            );
+            if !bodiless {
+                match body_len {
+                    Some(0) if head && hdrs.contains_key(header::CONTENT_LENGTH) => {}
+                    Some(body_len) => {
+                        let len = header::HeaderValue::from_str(&body_len.to_string()).unwrap();
+                        hdrs.insert(header::CONTENT_LENGTH, len);
+                    }
+                    None => {}
+                }
+            }
+        }
+        let mut header_block = Vec::new();
+        {
+            let fields = std::iter::once((&b":status"[..], status.as_str().as_bytes())).chain(
+                response
+                    .headers()
+                    .iter()
+                    .map(|(name, value)| (name.as_str().as_bytes(), value.as_bytes())),
+            );
+            hpack::encode(fields, &mut header_block);
+        }
+        let body = response.into_body();
+        if let Some(events) = body.events() {
+            events.watch(self.watcher.clone());
+            if self.going_away {
+                events.close();
+            }
+        }
+        let exhausted = head || !body.is_stream();
+        stream.response = Some(Outgoing {
+            header_block: Some(header_block),
+            body: if head { Body::empty() } else { body },
+            offset: 0,
+            chunk: Vec::new(),
+            exhausted,
+        });
+    }
+
+    /// Stop accepting streams, the connection closes once the open ones are done.
+    /// Returns `true` if the connection is idle and can be closed right away
+    pub fn drain(&mut self) -> bool {
+        if !self.going_away {
+            self.go_away(Reason::NO_ERROR);
+        }
+        // event streams would never end on their own
+        for events in self.event_streams() {
+            events.close();
+        }
+        self.streams.is_empty()
+    }
+
+    /// Queue a comment on the event streams waiting for their next events
+    pub fn keep_alive(&self) {
+        for events in self.event_streams() {
+            events.keep_alive();
+        }
+    }
+
+    /// What the connection is waiting on, `None` while waiting on handlers
+    pub fn phase(&self) -> Option<Phase> {
+        if self.wants_write() {
+            Some(Phase::Write)
+        } else if self.streams.values().any(|s| {
+            s.response
+                .as_ref()
+                .map(|r| !r.is_waiting())
+                .unwrap_or(false)
+        }) {
+            // waiting on the client to open its flow control window
+            Some(Phase::Write)
+        } else if self.streams.values().any(|s| !s.recv_closed) {
+            Some(Phase::Body)
+        } else if self.streams.values().any(|s| s.response.is_some()) {
+            Some(Phase::Events)
+        } else if !self.streams.is_empty() {
+            None
+        } else if self.continuation.is_some() || !self.read_buf.is_empty() {
+            Some(Phase::Header)
+        } else {
+            Some(Phase::Idle)
+        }
+    }
+
+    /// Whether there's something to write once the socket is writable
+    pub fn wants_write(&self) -> bool {
+        if self.written < self.write_buf.len() {
+            return true;
+        }
+        let window = self.send_window;
+        self.streams.values().any(|s| {
+            s.response
+                .as_ref()
+                .map(|r| r.sendable(window.min(s.send_window)))
+                .unwrap_or(false)
+        })
+    }
+
+    /// The event streams being sent as responses
+    fn event_streams(&self) -> impl Iterator<Item = &Receiver> {
+        self.streams
+            .values()
+            .filter_map(|s| s.response.as_ref().and_then(|r| r.body.events()))
+    }
+
+    /// Hand the request received on the stream numbered `id` to the handler, or
+    /// answer it with an error response
+    fn dispatch<D, F>(
+        &mut self,
+        id: u32,
+        ticket: Ticket,
+        dispatch: &D,
+        extend: &F,
+    ) -> Option<Response<Body>>
+    where
+        D: Dispatch,
+        F: Fn(&mut http::request::Parts),
+    {
+        let stream = self.streams.get_mut(&id)?;
+        if let Some(error) = stream.rejected.take() {
+            return Some(error_response(&self.error_handler, &error));
+        }
+        let mut parts = stream.head.take()?;
+        if stream.head_request && self.head_as_get {
+            parts.method = http::Method::GET;
+        }
+        if let Some(trailers) = stream.trailers.take() {
+            parts.extensions.insert(Trailers(trailers));
+        }
+        extend(&mut parts);
+        let body = std::mem::take(&mut stream.body);
+        let request = Request {
+            inner: http::Request::from_parts(parts, body),
+            body_start: 0,
+        };
+        debug!("{:?} - Dispatching HTTP/2 stream {}", ticket.token, id);
+        dispatch.dispatch(ticket, request)
+    }
+
+    /// Handle the complete frames in `read_buf`
+    fn process(&mut self) -> ConnResult<()> {
+        let mut buf = std::mem::take(&mut self.read_buf);
+        let mut pos = 0;
+        let res = self.frames(&buf, &mut pos);
+        // only the handled frames are dropped, a partial one stays where it is
+        buf.drain(..pos);
+        self.read_buf = buf;
+        res
+    }
+
+    /// Handle the complete frames in `buf` from `pos` on, moving `pos` past them
+    fn frames(&mut self, buf: &[u8], pos: &mut usize) -> ConnResult<()> {
+        if !self.preface_received {
+            let len = buf.len().min(PREFACE.len());
+            if buf[..len] != PREFACE[..len] {
+                return Err(Reason::PROTOCOL_ERROR);
+            }
+            if len < PREFACE.len() {
+                return Ok(());
+            }
+            self.preface_received = true;
+            *pos = PREFACE.len();
+        }
+        while buf.len() - *pos >= FRAME_HEADER_LEN && !self.failed {
+            let header = &buf[*pos..*pos + FRAME_HEADER_LEN];
+            let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+            if len > MAX_FRAME_SIZE {
+                return Err(Reason::FRAME_SIZE_ERROR);
+            }
+            if buf.len() - *pos < FRAME_HEADER_LEN + len {
+                break;
+            }
+            let id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & !(1 << 31);
+            let payload = &buf[*pos + FRAME_HEADER_LEN..*pos + FRAME_HEADER_LEN + len];
+            *pos += FRAME_HEADER_LEN + len;
+            self.frame(header[3], header[4], id, payload)?;
+        }
+        Ok(())
+    }
+
+    fn frame(&mut self, kind: u8, flags: u8, id: u32, payload: &[u8]) -> ConnResult<()> {
+        if !self.settings_received && kind != SETTINGS {
+            // the preface ends with a SETTINGS frame
+            return Err(Reason::PROTOCOL_ERROR);
+        }
+        if let Some((expected, _, _)) = self.continuation {
+            if kind != CONTINUATION || id != expected {
+                return Err(Reason::PROTOCOL_ERROR);
+            }
+        }
+        match kind {
+            DATA => self.data(flags, id, payload),
+            HEADERS => {
+                if id == 0 {
+                    return Err(Reason::PROTOCOL_ERROR);
+                }
+                let mut fragment = unpad(flags, payload)?;
+                if flags & PRIORITY_FLAG != 0 {
+                    fragment = fragment.get(5..).ok_or(Reason::FRAME_SIZE_ERROR)?;
+                }
+                let end_stream = flags & END_STREAM != 0;
+                if flags & END_HEADERS != 0 {
+                    self.header_block(id, end_stream, fragment)
+                } else {
+                    self.continuation = Some((id, end_stream, fragment.to_vec()));
+                    Ok(())
+                }
+            }
+            CONTINUATION => {
+                let (id, end_stream, mut block) =
+                    self.continuation.take().ok_or(Reason::PROTOCOL_ERROR)?;
+                block.extend_from_slice(payload);
+                if block.len() > self.limits.max_header_bytes + MAX_FRAME_SIZE {
+                    // too large to buffer, and skipping it would throw off the decoder
+                    return Err(Reason::ENHANCE_YOUR_CALM);
+                }
+                if flags & END_HEADERS != 0 {
+                    self.header_block(id, end_stream, &block)
+                } else {
+                    self.continuation = Some((id, end_stream, block));
+                    Ok(())
+                }
+            }
+            PRIORITY => {
+                if id == 0 {
+                    return Err(Reason::PROTOCOL_ERROR);
+                }
+                if payload.len() != 5 {
+                    self.reset(id, Reason::FRAME_SIZE_ERROR);
+                }
+                Ok(())
+            }
+            RST_STREAM => {
+                if id == 0 || id > self.last_stream_id {
+                    return Err(Reason::PROTOCOL_ERROR);
+                }
+                if payload.len() != 4 {
+                    return Err(Reason::FRAME_SIZE_ERROR);
+                }
+                self.streams.remove(&id);
+                Ok(())
+            }
+            SETTINGS => {
+                if id != 0 {
+                    return Err(Reason::PROTOCOL_ERROR);
+                }
+                if flags & ACK != 0 {
+                    return if payload.is_empty() {
+                        Ok(())
+                    } else {
+                        Err(Reason::FRAME_SIZE_ERROR)
+                    };
+                }
+                self.apply_settings(payload)?;
+                self.settings_received = true;
+                self.push_frame(SETTINGS, ACK, 0, &[]);
+                Ok(())
+            }
+            PUSH_PROMISE => Err(Reason::PROTOCOL_ERROR),
+            PING => {
+                if id != 0 {
+                    return Err(Reason::PROTOCOL_ERROR);
+                }
+                if payload.len() != 8 {
+                    return Err(Reason::FRAME_SIZE_ERROR);
+                }
+                if flags & ACK == 0 {
+                    self.push_frame(PING, ACK, 0, payload);
+                }
+                Ok(())
+            }
+            GOAWAY => {
+                if id != 0 {
+                    return Err(Reason::PROTOCOL_ERROR);
+                }
+                // streams we haven't seen yet won't come, the others are still answered
+                self.going_away = true;
+                Ok(())
+            }
+            WINDOW_UPDATE => {
+                if payload.len() != 4 {
+                    return Err(Reason::FRAME_SIZE_ERROR);
+                }
+                let increment = i64::from(
+                    u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]])
+                        & !(1 << 31),
+                );
+                if id == 0 {
+                    if increment == 0 {
+                        return Err(Reason::PROTOCOL_ERROR);
+                    }
+                    self.send_window += increment;
+                    if self.send_window > MAX_WINDOW {
+                        return Err(Reason::FLOW_CONTROL_ERROR);
+                    }
+                } else if increment == 0 {
+                    self.reset(id, Reason::PROTOCOL_ERROR);
+                } else if let Some(stream) = self.streams.get_mut(&id) {
+                    stream.send_window += increment;
+                    if stream.send_window > MAX_WINDOW {
+                        self.reset(id, Reason::FLOW_CONTROL_ERROR);
+                    }
+                }
+                Ok(())
+            }
+            // unknown frame types are ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn data(&mut self, flags: u8, id: u32, payload: &[u8]) -> ConnResult<()> {
+        if id == 0 || id > self.last_stream_id {
+            return Err(Reason::PROTOCOL_ERROR);
+        }
+        let data = unpad(flags, payload)?;
+        self.recv_window -= payload.len() as i64;
+        if self.recv_window < 0 {
+            return Err(Reason::FLOW_CONTROL_ERROR);
+        }
+        let end_stream = flags & END_STREAM != 0;
+        let max_body_bytes = self.limits.max_body_bytes;
+        match self.streams.get_mut(&id) {
+            Some(stream) if stream.recv_closed => {
+                self.reset(id, Reason::STREAM_CLOSED);
+                return Ok(());
+            }
+            Some(stream) => {
+                stream.recv_window -= payload.len() as i64;
+                if stream.recv_window < 0 {
+                    self.reset(id, Reason::FLOW_CONTROL_ERROR);
+                    return Ok(());
+                }
+            }
+            // closed already, frames the client sent before it learnt about it
+            None => return Ok(()),
+        }
+        let stream = self.streams.get_mut(&id).unwrap();
+        if stream.head.is_some() {
+            if stream.body.len() + data.len() > max_body_bytes {
+                stream.rejected = Some(
+                    ErrorKind::RequestBodyTooLarge(format!(
+                        "Request body is larger than limit: {}",
+                        max_body_bytes
+                    ))
+                    .into(),
+                );
+                stream.head = None;
+                stream.body = Vec::new();
+                self.ready.push(id);
+            } else {
+                stream.body.extend_from_slice(data);
+            }
+        }
+        if end_stream {
+            stream.recv_closed = true;
+            if stream.head.is_some() {
+                self.ready.push(id);
+            }
+        } else if stream.head.is_some() {
+            // only needed when the body limit doesn't fit in a window
+            let left = (max_body_bytes - stream.body.len()).min(MAX_WINDOW as usize);
+            let target = self.stream_window.min(left as i64 + 1);
+            let increment = target - stream.recv_window;
+            if increment > 0 && stream.recv_window <= target / 2 {
+                stream.recv_window += increment;
+                self.push_window_update(id, increment as usize);
+            }
+        }
+        Ok(())
+    }
+
+    /// Open the connection's flow control window back up to what's left of the
+    /// budget for buffered request bodies, once the client used half of it
+    fn replenish(&mut self) {
+        let buffered: usize = self.streams.values().map(|s| s.body.len()).sum();
+        let target = self.stream_window - buffered as i64;
+        let increment = target - self.recv_window;
+        if increment > 0 && self.recv_window <= target / 2 {
+            self.recv_window += increment;
+            self.push_window_update(0, increment as usize);
+        }
+    }
+
+    /// Handle a complete header block, opening a stream or ending one with trailers
+    fn header_block(&mut self, id: u32, end_stream: bool, block: &[u8]) -> ConnResult<()> {
+        let (fields, truncated) = self
+            .decoder
+            .decode(block, self.limits.max_header_bytes)
+            .ok_or(Reason::COMPRESSION_ERROR)?;
+
+        if let Some(stream) = self.streams.get_mut(&id) {
+            if stream.recv_closed || !end_stream {
+                self.reset(id, Reason::PROTOCOL_ERROR);
+                return Ok(());
+            }
+            stream.recv_closed = true;
+            if stream.head.is_some() {
+                let mut trailers = http::HeaderMap::new();
+                for (name, value) in fields {
+                    if let (Ok(name), Ok(value)) = (
+                        header::HeaderName::from_bytes(&name),
+                        header::HeaderValue::from_bytes(&value),
+                    ) {
+                        trailers.append(name, value);
+                    }
+                }
+                stream.trailers = Some(trailers);
+                self.ready.push(id);
+            }
+            return Ok(());
+        }
+        if id <= self.last_stream_id {
+            // a stream that's closed already, e.g. reset by us
+            return Ok(());
+        }
+        if id & 1 == 0 {
+            return Err(Reason::PROTOCOL_ERROR);
+        }
+        self.last_stream_id = id;
+        if self.going_away {
+            return Ok(());
+        }
+        if self.streams.len() >= MAX_CONCURRENT_STREAMS {
+            self.reset(id, Reason::REFUSED_STREAM);
+            return Ok(());
+        }
+
+        let mut stream = Stream::new(self.initial_window, self.stream_window);
+        stream.recv_closed = end_stream;
+        let head = if truncated {
+            Err(ErrorKind::RequestHeadersTooLarge(format!(
+                "Headers are larger than limit: {}",
+                self.limits.max_header_bytes
+            ))
+            .into())
+        } else {
+            request_head(fields, &self.limits)
+        };
+        match head {
+            Ok(head) => {
+                stream.head_request = head.method == http::Method::HEAD;
+                stream.head = Some(head);
+                if end_stream {
+                    self.ready.push(id);
+                }
+            }
+            Err(e) => {
+                stream.rejected = Some(e);
+                self.ready.push(id);
+            }
+        }
+        self.streams.insert(id, stream);
+        Ok(())
+    }
+
+    fn apply_settings(&mut self, payload: &[u8]) -> ConnResult<()> {
+        let settings = payload.chunks_exact(6);
+        if !settings.remainder().is_empty() {
+            return Err(Reason::FRAME_SIZE_ERROR);
+        }
+        for setting in settings {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => return Err(Reason::PROTOCOL_ERROR),
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let window = i64::from(value);
+                    if window > MAX_WINDOW {
+                        return Err(Reason::FLOW_CONTROL_ERROR);
+                    }
+                    let delta = window - self.initial_window;
+                    self.initial_window = window;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                        if stream.send_window > MAX_WINDOW {
+                            return Err(Reason::FLOW_CONTROL_ERROR);
+                        }
+                    }
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if value < MAX_FRAME_SIZE as u32 || value > (1 << 24) - 1 {
+                        return Err(Reason::PROTOCOL_ERROR);
+                    }
+                    self.max_frame_size = value as usize;
+                }
+                // the encoder doesn't use the dynamic table, push isn't supported
+                // and we don't open streams
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Queue frames of the responses that are ready, taking turns between streams.
+    /// Returns whether anything was queued
+    fn produce(&mut self, budget: &mut usize) -> bool {
+        let mut produced = false;
+        loop {
+            let mut progress = false;
+            let ids: Vec<u32> = self
+                .streams
+                .iter()
+                .filter(|&(_, s)| s.response.is_some())
+                .map(|(&id, _)| id)
+                .collect();
+            for id in ids {
+                if self.write_buf.len() - self.written >= WRITE_BUFFER_SIZE || *budget == 0 {
+                    return produced;
+                }
+                progress |= self.produce_frame(id, budget);
+            }
+            if !progress {
+                return produced;
+            }
+            produced = true;
+        }
+    }
+
+    /// Queue the next frame of the response on the stream numbered `id`.
+    /// Returns whether anything was queued
+    fn produce_frame(&mut self, id: u32, budget: &mut usize) -> bool {
+        let max_frame_size = self.max_frame_size;
+        let conn_window = self.send_window;
+        let stream = self.streams.get_mut(&id).unwrap();
+        let window = conn_window.min(stream.send_window);
+        let resp = stream.response.as_mut().unwrap();
+        let fin = if let Some(block) = resp.header_block.take() {
+            let fin = resp.exhausted && resp.pending().is_empty();
+            let flags = if fin { END_STREAM } else { 0 };
+            push_header_block(&mut self.write_buf, max_frame_size, id, flags, &block);
+            fin
+        } else {
+            if resp.pending().is_empty() && !resp.exhausted {
+                // the next chunk of a streamed body
+                match resp.body.next_chunk() {
+                    Ok(Some(chunk)) => {
+                        resp.chunk = chunk;
+                        resp.offset = 0;
+                    }
+                    Ok(None) => resp.exhausted = true,
+                    Err(e) => {
+                        error!(
+                            "Stream {} - Failed to produce the response body: {:?}",
+                            id, e
+                        );
+                        self.reset(id, Reason::INTERNAL_ERROR);
+                        return true;
+                    }
+                }
+            }
+            let pending = resp.pending();
+            let n = pending
+                .len()
+                .min(window.max(0) as usize)
+                .min(max_frame_size);
+            let fin = resp.exhausted && n == pending.len();
+            if n == 0 && !fin {
+                // blocked on flow control, or an empty chunk
+                return false;
+            }
+            let flags = if fin { END_STREAM } else { 0 };
+            push_frame(&mut self.write_buf, DATA, flags, id, &pending[..n]);
+            resp.offset += n;
+            stream.send_window -= n as i64;
+            self.send_window -= n as i64;
+            *budget = budget.saturating_sub(n);
+            fin
+        };
+        if fin {
+            let recv_closed = stream.recv_closed;
+            self.streams.remove(&id);
+            if !recv_closed {
+                // we're done, the rest of the request isn't needed
+                self.reset(id, Reason::NO_ERROR);
+            }
+        }
+        true
+    }
+
+    /// Write queued frames. Returns `false` once `stream` doesn't take more
+    fn write(&mut self, stream: &mut Transport) -> io::Result<bool> {
+        while self.written < self.write_buf.len() {
+            match stream.write(&self.write_buf[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.written += n;
+                    self.wrote = true;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+        self.write_buf.clear();
+        self.written = 0;
+        match stream.flush() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+            Ok(()) => Ok(true),
+        }
+    }
+
+    fn send_settings(&mut self) {
+        let mut payload = Vec::new();
+        for &(id, value) in &[
+            (
+                SETTINGS_MAX_CONCURRENT_STREAMS,
+                MAX_CONCURRENT_STREAMS as u32,
+            ),
+            (SETTINGS_HEADER_TABLE_SIZE, hpack::TABLE_SIZE as u32),
+            (SETTINGS_INITIAL_WINDOW_SIZE, self.stream_window as u32),
+            (
+                SETTINGS_MAX_HEADER_LIST_SIZE,
+                self.limits.max_header_bytes as u32,
+            ),
+        ] {
+            payload.extend_from_slice(&id.to_be_bytes());
+            payload.extend_from_slice(&value.to_be_bytes());
+        }
+        self.push_frame(SETTINGS, 0, 0, &payload);
+    }
+
+    /// Tell the client no more streams are accepted. With an error, the connection
+    /// is closed once that's written
+    fn go_away(&mut self, reason: Reason) {
+        self.going_away = true;
+        if reason != Reason::NO_ERROR {
+            self.failed = true;
+            self.streams.clear();
+        }
+        let mut payload = self.last_stream_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&reason.0.to_be_bytes());
+        self.push_frame(GOAWAY, 0, 0, &payload);
+    }
+
+    /// Close the stream numbered `id` with a stream error
+    fn reset(&mut self, id: u32, reason: Reason) {
+        self.streams.remove(&id);
+        self.push_frame(RST_STREAM, 0, id, &reason.0.to_be_bytes());
+    }
+
+    fn push_window_update(&mut self, id: u32, increment: usize) {
+        self.push_frame(WINDOW_UPDATE, 0, id, &(increment as u32).to_be_bytes());
+    }
+
+    fn push_frame(&mut self, kind: u8, flags: u8, id: u32, payload: &[u8]) {
+        push_frame(&mut self.write_buf, kind, flags, id, payload);
+    }
+}
+
+/// Queue a header block, split into `CONTINUATION` frames of at most
+/// `max_frame_size` as needed
+fn push_header_block(buf: &mut Vec<u8>, max_frame_size: usize, id: u32, flags: u8, block: &[u8]) {
+    let mut fragments = block.chunks(max_frame_size).peekable();
+    let mut kind = HEADERS;
+    loop {
+        let fragment = fragments.next().unwrap_or(&[]);
+        let end = if fragments.peek().is_none() {
+            END_HEADERS
+        } else {
+            0
+        };
+        // END_STREAM belongs on the HEADERS frame
+        let flags = if kind == HEADERS { flags | end } else { end };
+        push_frame(buf, kind, flags, id, fragment);
+        if end != 0 {
+            return;
+        }
+        kind = CONTINUATION;
+    }
+}
+
+fn push_frame(buf: &mut Vec<u8>, kind: u8, flags: u8, id: u32, payload: &[u8]) {
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    buf.push(kind);
+    buf.push(flags);
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(payload);
+}
+
+/// The payload of a frame without its padding
+fn unpad(flags: u8, payload: &[u8]) -> ConnResult<&[u8]> {
+    if flags & PADDED == 0 {
+        return Ok(payload);
+    }
+    let (&pad, rest) = payload.split_first().ok_or(Reason::FRAME_SIZE_ERROR)?;
+    let len = rest
+        .len()
+        .checked_sub(pad as usize)
+        .ok_or(Reason::PROTOCOL_ERROR)?;
+    Ok(&rest[..len])
+}
+
+/// Turn the header fields opening a stream into the head of a request
+fn request_head(fields: Vec<hpack::Header>, limits: &Limits) -> Result<http::request::Parts> {
+    let (mut parts, _) = http::Request::new(()).into_parts();
+    parts.version = http::Version::HTTP_2;
+    let mut method = None;
+    let mut scheme = None;
+    let mut authority = None;
+    let mut path = None;
+    let mut cookies: Vec<Vec<u8>> = Vec::new();
+    let mut regular = false;
+    let mut count = 0;
+    for (name, value) in fields {
+        if name.starts_with(b":") {
+            // pseudo-header fields come first, once each
+            let field = match &name[..] {
+                b":method" => &mut method,
+                b":scheme" => &mut scheme,
+                b":authority" => &mut authority,
+                b":path" => &mut path,
+                _ => bail_fmt!(
+                    ErrorKind::MalformedHttpRequest,
+                    "Unknown pseudo-header field"
+                ),
+            };
+            if regular || field.is_some() {
+                bail_fmt!(
+                    ErrorKind::MalformedHttpRequest,
+                    "Misplaced pseudo-header field"
+                );
+            }
+            *field = Some(value);
+            continue;
+        }
+        regular = true;
+        count += 1;
+        if count > limits.max_headers {
+            bail_fmt!(
+                ErrorKind::RequestHeadersTooLarge,
+                "Too many headers, limit: {}",
+                limits.max_headers
+            );
+        }
+        if name.iter().any(u8::is_ascii_uppercase)
+            || CONNECTION_HEADERS
+                .iter()
+                .any(|h| name == h.as_str().as_bytes())
+            || &name[..] == b"keep-alive"
+            || &name[..] == b"proxy-connection"
+            || (&name[..] == b"te" && &value[..] != b"trailers")
+        {
+            bail_fmt!(
+                ErrorKind::MalformedHttpRequest,
+                "Invalid header field: {}",
+                String::from_utf8_lossy(&name)
+            );
+        }
+        if &name[..] == b"cookie" {
+            // crumbs of the same cookie header, put back together
+            cookies.push(value);
+            continue;
+        }
+        let name = header::HeaderName::from_bytes(&name)
+            .chain_err(|| format_err!(ErrorKind::MalformedHttpRequest, "Invalid header name"))?;
+        let value = header::HeaderValue::from_bytes(&value)
+            .chain_err(|| format_err!(ErrorKind::MalformedHttpRequest, "Invalid header value"))?;
+        parts.headers.append(name, value);
+    }
+    if !cookies.is_empty() {
+        let value = header::HeaderValue::from_bytes(&cookies.join(&b"; "[..]))
+            .chain_err(|| format_err!(ErrorKind::MalformedHttpRequest, "Invalid cookie"))?;
+        parts.headers.insert(header::COOKIE, value);
+    }
+
+    let method = match method {
+        Some(method) => http::Method::from_bytes(&method)
+            .chain_err(|| format_err!(ErrorKind::MalformedHttpRequest, "Invalid method"))?,
+        None => bail_fmt!(ErrorKind::MalformedHttpRequest, "Missing :method"),
+    };
+    let path = match path {
+        Some(path) => path,
+        None if method == http::Method::CONNECT => Vec::new(),
+        None => bail_fmt!(ErrorKind::MalformedHttpRequest, "Missing :path"),
+    };
+    if path.len() > limits.max_uri_length {
+        bail_fmt!(
+            ErrorKind::UriTooLong,
+            "Request target is longer than limit: {}",
+            limits.max_uri_length
+        );
+    }
+    let mut uri = http::Uri::builder();
+    if let Some(ref authority) = authority {
+        if !parts.headers.contains_key(header::HOST) {
+            let host = header::HeaderValue::from_bytes(authority)
+                .chain_err(|| format_err!(ErrorKind::MalformedHttpRequest, "Invalid :authority"))?;
+            parts.headers.insert(header::HOST, host);
+        }
+        uri = uri.authority(&authority[..]);
+        if let Some(ref scheme) = scheme {
+            uri = uri.scheme(&scheme[..]);
+        }
+    }
+    if !path.is_empty() {
+        uri = uri.path_and_query(&path[..]);
+    }
+    parts.uri = uri
+        .build()
+        .chain_err(|| format_err!(ErrorKind::MalformedHttpRequest, "Invalid request target"))?;
+    parts.method = method;
+    Ok(parts)
+}
@@ -0,0 +1,940 @@
+//! HPACK, the header compression of HTTP/2 (RFC 7541).
+//!
+//! Decoding supports everything a client may send. Encoding only refers to the
+//! static table and sends the rest as plain literals, so the client's dynamic
+//! table is never used and doesn't need to be kept in sync.
+use std::collections::VecDeque;
+
+/// Size of the dynamic table we allow clients to use, the protocol's default
+/// `SETTINGS_HEADER_TABLE_SIZE`
+pub(crate) const TABLE_SIZE: usize = 4096;
+
+/// Marks a Huffman tree node child as a leaf, the low bits are the symbol
+const LEAF: u16 = 0x8000;
+/// Huffman symbol of the end of string, which must never be decoded
+const EOS: u16 = 256;
+
+/// A decoded header field, name and value
+pub(crate) type Header = (Vec<u8>, Vec<u8>);
+
+lazy_static! {
+    /// Huffman decoding tree shared by all decoders, the root is the first node
+    static ref HUFFMAN_TREE: Vec<[u16; 2]> = huffman_tree();
+}
+
+/// Decodes the header blocks of one connection, keeping the dynamic table across them
+pub(crate) struct Decoder {
+    /// Most recent entry first
+    table: VecDeque<Header>,
+    size: usize,
+    /// Set by the client through dynamic table size updates, up to `TABLE_SIZE`
+    max_size: usize,
+}
+impl Decoder {
+    pub fn new() -> Self {
+        Self {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: TABLE_SIZE,
+        }
+    }
+
+    /// Decode a complete header block. Fields are collected until their size, as
+    /// counted by `SETTINGS_MAX_HEADER_LIST_SIZE`, goes over `max_list_size`, the
+    /// rest of the block is still decoded to keep the dynamic table in sync.
+    /// Returns the fields and whether some were left out, or `None` if the block
+    /// is malformed, which is fatal to the connection
+    pub fn decode(&mut self, block: &[u8], max_list_size: usize) -> Option<(Vec<Header>, bool)> {
+        let mut headers = Vec::new();
+        let mut list_size = 0;
+        let mut truncated = false;
+        let mut pos = 0;
+        while pos < block.len() {
+            let first = block[pos];
+            let header = if first & 0x80 != 0 {
+                // indexed field
+                let index = integer(block, &mut pos, 7)?;
+                self.get(index)?
+            } else if first & 0xc0 == 0x40 {
+                // literal field, added to the dynamic table
+                let header = self.literal(block, &mut pos, 6)?;
+                self.insert(header.clone());
+                header
+            } else if first & 0xe0 == 0x20 {
+                // dynamic table size update
+                let max_size = integer(block, &mut pos, 5)?;
+                if max_size > TABLE_SIZE {
+                    return None;
+                }
+                self.max_size = max_size;
+                self.evict(0);
+                continue;
+            } else {
+                // literal field, without indexing or never indexed
+                self.literal(block, &mut pos, 4)?
+            };
+            list_size += header.0.len() + header.1.len() + 32;
+            if list_size > max_list_size {
+                truncated = true;
+            } else {
+                headers.push(header);
+            }
+        }
+        Some((headers, truncated))
+    }
+
+    /// A literal field whose name is indexed with a `prefix` bits integer, or
+    /// follows as a string when the index is 0
+    fn literal(&self, block: &[u8], pos: &mut usize, prefix: u8) -> Option<Header> {
+        let name = match integer(block, pos, prefix)? {
+            0 => self.string(block, pos)?,
+            index => self.get(index)?.0,
+        };
+        let value = self.string(block, pos)?;
+        Some((name, value))
+    }
+
+    /// The entry at `index` of the static table followed by the dynamic table
+    fn get(&self, index: usize) -> Option<Header> {
+        if index == 0 {
+            None
+        } else if index <= STATIC_TABLE.len() {
+            let (name, value) = STATIC_TABLE[index - 1];
+            Some((name.to_vec(), value.to_vec()))
+        } else {
+            self.table.get(index - STATIC_TABLE.len() - 1).cloned()
+        }
+    }
+
+    fn insert(&mut self, header: Header) {
+        let size = header.0.len() + header.1.len() + 32;
+        self.evict(size);
+        // an entry larger than the table empties it without being added
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front(header);
+        }
+    }
+
+    /// Evict the oldest entries until `room` more bytes fit in the table
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size {
+            match self.table.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + 32,
+                None => break,
+            }
+        }
+    }
+
+    fn string(&self, block: &[u8], pos: &mut usize) -> Option<Vec<u8>> {
+        let huffman = *block.get(*pos)? & 0x80 != 0;
+        let len = integer(block, pos, 7)?;
+        let end = pos.checked_add(len)?;
+        let data = block.get(*pos..end)?;
+        *pos = end;
+        if huffman {
+            self.huffman_decode(data)
+        } else {
+            Some(data.to_vec())
+        }
+    }
+
+    fn huffman_decode(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let mut decoded = Vec::with_capacity(data.len() * 8 / 5);
+        let mut node = 0;
+        // bits read since the last symbol, they must be at most 7 bits of padding
+        // made of the most significant bits of EOS, all ones, at the end
+        let mut pending = 0;
+        let mut all_ones = true;
+        for &byte in data {
+            for shift in (0..8).rev() {
+                let bit = (byte >> shift) & 1;
+                let next = HUFFMAN_TREE[node][bit as usize];
+                if next & LEAF != 0 {
+                    let symbol = next & !LEAF;
+                    if symbol == EOS {
+                        return None;
+                    }
+                    decoded.push(symbol as u8);
+                    node = 0;
+                    pending = 0;
+                    all_ones = true;
+                } else if next == 0 {
+                    return None;
+                } else {
+                    node = next as usize;
+                    pending += 1;
+                    all_ones &= bit == 1;
+                }
+            }
+        }
+        if pending > 7 || !all_ones {
+            return None;
+        }
+        Some(decoded)
+    }
+}
+
+/// Encode header fields into a header block, `:status` and other pseudo-header
+/// fields first
+pub(crate) fn encode<'a, I>(headers: I, block: &mut Vec<u8>)
+where
+    I: IntoIterator<Item = (&'a [u8], &'a [u8])>,
+{
+    for (name, value) in headers {
+        let mut name_index = 0;
+        let mut index = 0;
+        for (i, &(n, v)) in STATIC_TABLE.iter().enumerate() {
+            if n == name {
+                if v == value {
+                    index = i + 1;
+                    break;
+                }
+                if name_index == 0 {
+                    name_index = i + 1;
+                }
+            }
+        }
+        if index != 0 {
+            put_integer(block, index, 7, 0x80);
+            continue;
+        }
+        // literal field without indexing
+        put_integer(block, name_index, 4, 0);
+        if name_index == 0 {
+            put_string(block, name);
+        }
+        put_string(block, value);
+    }
+}
+
+/// Decode an integer whose first byte holds `prefix` bits of it
+fn integer(block: &[u8], pos: &mut usize, prefix: u8) -> Option<usize> {
+    let max = (1u8 << prefix) - 1;
+    let first = *block.get(*pos)? & max;
+    *pos += 1;
+    if first < max {
+        return Some(first as usize);
+    }
+    let mut value = max as usize;
+    let mut shift = 0;
+    loop {
+        let byte = *block.get(*pos)?;
+        *pos += 1;
+        // anything larger is bogus for a length or an index
+        if shift > 28 {
+            return None;
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+}
+
+fn put_integer(block: &mut Vec<u8>, mut value: usize, prefix: u8, flags: u8) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        block.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+/// A string, never Huffman encoded
+fn put_string(block: &mut Vec<u8>, s: &[u8]) {
+    put_integer(block, s.len(), 7, 0);
+    block.extend_from_slice(s);
+}
+
+fn huffman_tree() -> Vec<[u16; 2]> {
+    let mut nodes = vec![[0u16; 2]];
+    for (symbol, &(code, len)) in HUFFMAN_CODES.iter().enumerate() {
+        let mut node = 0;
+        for shift in (0..len).rev() {
+            let bit = ((code >> shift) & 1) as usize;
+            if shift == 0 {
+                nodes[node][bit] = LEAF | symbol as u16;
+            } else {
+                if nodes[node][bit] == 0 {
+                    nodes.push([0; 2]);
+                    nodes[node][bit] = (nodes.len() - 1) as u16;
+                }
+                node = nodes[node][bit] as usize;
+            }
+        }
+    }
+    nodes
+}
+
+/// The static table, its first entry is index 1
+static STATIC_TABLE: &[(&[u8], &[u8])] = &[
+    (b":authority", b""),
+    (b":method", b"GET"),
+    (b":method", b"POST"),
+    (b":path", b"/"),
+    (b":path", b"/index.html"),
+    (b":scheme", b"http"),
+    (b":scheme", b"https"),
+    (b":status", b"200"),
+    (b":status", b"204"),
+    (b":status", b"206"),
+    (b":status", b"304"),
+    (b":status", b"400"),
+    (b":status", b"404"),
+    (b":status", b"500"),
+    (b"accept-charset", b""),
+    (b"accept-encoding", b"gzip, deflate"),
+    (b"accept-language", b""),
+    (b"accept-ranges", b""),
+    (b"accept", b""),
+    (b"access-control-allow-origin", b""),
+    (b"age", b""),
+    (b"allow", b""),
+    (b"authorization", b""),
+    (b"cache-control", b""),
+    (b"content-disposition", b""),
+    (b"content-encoding", b""),
+    (b"content-language", b""),
+    (b"content-length", b""),
+    (b"content-location", b""),
+    (b"content-range", b""),
+    (b"content-type", b""),
+    (b"cookie", b""),
+    (b"date", b""),
+    (b"etag", b""),
+    (b"expect", b""),
+    (b"expires", b""),
+    (b"from", b""),
+    (b"host", b""),
+    (b"if-match", b""),
+    (b"if-modified-since", b""),
+    (b"if-none-match", b""),
+    (b"if-range", b""),
+    (b"if-unmodified-since", b""),
+    (b"last-modified", b""),
+    (b"link", b""),
+    (b"location", b""),
+    (b"max-forwards", b""),
+    (b"proxy-authenticate", b""),
+    (b"proxy-authorization", b""),
+    (b"range", b""),
+    (b"referer", b""),
+    (b"refresh", b""),
+    (b"retry-after", b""),
+    (b"server", b""),
+    (b"set-cookie", b""),
+    (b"strict-transport-security", b""),
+    (b"transfer-encoding", b""),
+    (b"user-agent", b""),
+    (b"vary", b""),
+    (b"via", b""),
+    (b"www-authenticate", b""),
+];
+
+/// Huffman code of each symbol, and its length in bits. The last one is EOS
+static HUFFMAN_CODES: &[(u32, u8)] = &[
+    (0x1ff8, 13),
+    (0x7fffd8, 23),
+    (0xfffffe2, 28),
+    (0xfffffe3, 28),
+    (0xfffffe4, 28),
+    (0xfffffe5, 28),
+    (0xfffffe6, 28),
+    (0xfffffe7, 28),
+    (0xfffffe8, 28),
+    (0xffffea, 24),
+    (0x3ffffffc, 30),
+    (0xfffffe9, 28),
+    (0xfffffea, 28),
+    (0x3ffffffd, 30),
+    (0xfffffeb, 28),
+    (0xfffffec, 28),
+    (0xfffffed, 28),
+    (0xfffffee, 28),
+    (0xfffffef, 28),
+    (0xffffff0, 28),
+    (0xffffff1, 28),
+    (0xffffff2, 28),
+    (0x3ffffffe, 30),
+    (0xffffff3, 28),
+    (0xffffff4, 28),
+    (0xffffff5, 28),
+    (0xffffff6, 28),
+    (0xffffff7, 28),
+    (0xffffff8, 28),
+    (0xffffff9, 28),
+    (0xffffffa, 28),
+    (0xffffffb, 28),
+    (0x14, 6),
+    (0x3f8, 10),
+    (0x3f9, 10),
+    (0xffa, 12),
+    (0x1ff9, 13),
+    (0x15, 6),
+    (0xf8, 8),
+    (0x7fa, 11),
+    (0x3fa, 10),
+    (0x3fb, 10),
+    (0xf9, 8),
+    (0x7fb, 11),
+    (0xfa, 8),
+    (0x16, 6),
+    (0x17, 6),
+    (0x18, 6),
+    (0x0, 5),
+    (0x1, 5),
+    (0x2, 5),
+    (0x19, 6),
+    (0x1a, 6),
+    (0x1b, 6),
+    (0x1c, 6),
+    (0x1d, 6),
+    (0x1e, 6),
+    (0x1f, 6),
+    (0x5c, 7),
+    (0xfb, 8),
+    (0x7ffc, 15),
+    (0x20, 6),
+    (0xffb, 12),
+    (0x3fc, 10),
+    (0x1ffa, 13),
+    (0x21, 6),
+    (0x5d, 7),
+    (0x5e, 7),
+    (0x5f, 7),
+    (0x60, 7),
+    (0x61, 7),
+    (0x62, 7),
+    (0x63, 7),
+    (0x64, 7),
+    (0x65, 7),
+    (0x66, 7),
+    (0x67, 7),
+    (0x68, 7),
+    (0x69, 7),
+    (0x6a, 7),
+    (0x6b, 7),
+    (0x6c, 7),
+    (0x6d, 7),
+    (0x6e, 7),
+    (0x6f, 7),
+    (0x70, 7),
+    (0x71, 7),
+    (0x72, 7),
+    (0xfc, 8),
+    (0x73, 7),
+    (0xfd, 8),
+    (0x1ffb, 13),
+    (0x7fff0, 19),
+    (0x1ffc, 13),
+    (0x3ffc, 14),
+    (0x22, 6),
+    (0x7ffd, 15),
+    (0x3, 5),
+    (0x23, 6),
+    (0x4, 5),
+    (0x24, 6),
+    (0x5, 5),
+    (0x25, 6),
+    (0x26, 6),
+    (0x27, 6),
+    (0x6, 5),
+    (0x74, 7),
+    (0x75, 7),
+    (0x28, 6),
+    (0x29, 6),
+    (0x2a, 6),
+    (0x7, 5),
+    (0x2b, 6),
+    (0x76, 7),
+    (0x2c, 6),
+    (0x8, 5),
+    (0x9, 5),
+    (0x2d, 6),
+    (0x77, 7),
+    (0x78, 7),
+    (0x79, 7),
+    (0x7a, 7),
+    (0x7b, 7),
+    (0x7ffe, 15),
+    (0x7fc, 11),
+    (0x3ffd, 14),
+    (0x1ffd, 13),
+    (0xffffffc, 28),
+    (0xfffe6, 20),
+    (0x3fffd2, 22),
+    (0xfffe7, 20),
+    (0xfffe8, 20),
+    (0x3fffd3, 22),
+    (0x3fffd4, 22),
+    (0x3fffd5, 22),
+    (0x7fffd9, 23),
+    (0x3fffd6, 22),
+    (0x7fffda, 23),
+    (0x7fffdb, 23),
+    (0x7fffdc, 23),
+    (0x7fffdd, 23),
+    (0x7fffde, 23),
+    (0xffffeb, 24),
+    (0x7fffdf, 23),
+    (0xffffec, 24),
+    (0xffffed, 24),
+    (0x3fffd7, 22),
+    (0x7fffe0, 23),
+    (0xffffee, 24),
+    (0x7fffe1, 23),
+    (0x7fffe2, 23),
+    (0x7fffe3, 23),
+    (0x7fffe4, 23),
+    (0x1fffdc, 21),
+    (0x3fffd8, 22),
+    (0x7fffe5, 23),
+    (0x3fffd9, 22),
+    (0x7fffe6, 23),
+    (0x7fffe7, 23),
+    (0xffffef, 24),
+    (0x3fffda, 22),
+    (0x1fffdd, 21),
+    (0xfffe9, 20),
+    (0x3fffdb, 22),
+    (0x3fffdc, 22),
+    (0x7fffe8, 23),
+    (0x7fffe9, 23),
+    (0x1fffde, 21),
+    (0x7fffea, 23),
+    (0x3fffdd, 22),
+    (0x3fffde, 22),
+    (0xfffff0, 24),
+    (0x1fffdf, 21),
+    (0x3fffdf, 22),
+    (0x7fffeb, 23),
+    (0x7fffec, 23),
+    (0x1fffe0, 21),
+    (0x1fffe1, 21),
+    (0x3fffe0, 22),
+    (0x1fffe2, 21),
+    (0x7fffed, 23),
+    (0x3fffe1, 22),
+    (0x7fffee, 23),
+    (0x7fffef, 23),
+    (0xfffea, 20),
+    (0x3fffe2, 22),
+    (0x3fffe3, 22),
+    (0x3fffe4, 22),
+    (0x7ffff0, 23),
+    (0x3fffe5, 22),
+    (0x3fffe6, 22),
+    (0x7ffff1, 23),
+    (0x3ffffe0, 26),
+    (0x3ffffe1, 26),
+    (0xfffeb, 20),
+    (0x7fff1, 19),
+    (0x3fffe7, 22),
+    (0x7ffff2, 23),
+    (0x3fffe8, 22),
+    (0x1ffffec, 25),
+    (0x3ffffe2, 26),
+    (0x3ffffe3, 26),
+    (0x3ffffe4, 26),
+    (0x7ffffde, 27),
+    (0x7ffffdf, 27),
+    (0x3ffffe5, 26),
+    (0xfffff1, 24),
+    (0x1ffffed, 25),
+    (0x7fff2, 19),
+    (0x1fffe3, 21),
+    (0x3ffffe6, 26),
+    (0x7ffffe0, 27),
+    (0x7ffffe1, 27),
+    (0x3ffffe7, 26),
+    (0x7ffffe2, 27),
+    (0xfffff2, 24),
+    (0x1fffe4, 21),
+    (0x1fffe5, 21),
+    (0x3ffffe8, 26),
+    (0x3ffffe9, 26),
+    (0xffffffd, 28),
+    (0x7ffffe3, 27),
+    (0x7ffffe4, 27),
+    (0x7ffffe5, 27),
+    (0xfffec, 20),
+    (0xfffff3, 24),
+    (0xfffed, 20),
+    (0x1fffe6, 21),
+    (0x3fffe9, 22),
+    (0x1fffe7, 21),
+    (0x1fffe8, 21),
+    (0x7ffff3, 23),
+    (0x3fffea, 22),
+    (0x3fffeb, 22),
+    (0x1ffffee, 25),
+    (0x1ffffef, 25),
+    (0xfffff4, 24),
+    (0xfffff5, 24),
+    (0x3ffffea, 26),
+    (0x7ffff4, 23),
+    (0x3ffffeb, 26),
+    (0x7ffffe6, 27),
+    (0x3ffffec, 26),
+    (0x3ffffed, 26),
+    (0x7ffffe7, 27),
+    (0x7ffffe8, 27),
+    (0x7ffffe9, 27),
+    (0x7ffffea, 27),
+    (0x7ffffeb, 27),
+    (0xffffffe, 28),
+    (0x7ffffec, 27),
+    (0x7ffffed, 27),
+    (0x7ffffee, 27),
+    (0x7ffffef, 27),
+    (0x7fffff0, 27),
+    (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 7541 Appendix A, index, name and value
+    const RFC_STATIC_TABLE: &str = "\
+1 :authority
+2 :method GET
+3 :method POST
+4 :path /
+5 :path /index.html
+6 :scheme http
+7 :scheme https
+8 :status 200
+9 :status 204
+10 :status 206
+11 :status 304
+12 :status 400
+13 :status 404
+14 :status 500
+15 accept-charset
+16 accept-encoding gzip, deflate
+17 accept-language
+18 accept-ranges
+19 accept
+20 access-control-allow-origin
+21 age
+22 allow
+23 authorization
+24 cache-control
+25 content-disposition
+26 content-encoding
+27 content-language
+28 content-length
+29 content-location
+30 content-range
+31 content-type
+32 cookie
+33 date
+34 etag
+35 expect
+36 expires
+37 from
+38 host
+39 if-match
+40 if-modified-since
+41 if-none-match
+42 if-range
+43 if-unmodified-since
+44 last-modified
+45 link
+46 location
+47 max-forwards
+48 proxy-authenticate
+49 proxy-authorization
+50 range
+51 referer
+52 refresh
+53 retry-after
+54 server
+55 set-cookie
+56 strict-transport-security
+57 transfer-encoding
+58 user-agent
+59 vary
+60 via
+61 www-authenticate";
+
+    #[test]
+    fn static_table_matches_rfc() {
+        let expected = RFC_STATIC_TABLE.lines().collect::<Vec<_>>();
+        assert_eq!(STATIC_TABLE.len(), expected.len());
+        let decoder = Decoder::new();
+        for line in expected {
+            let mut parts = line.splitn(3, ' ');
+            let index: usize = parts.next().unwrap().parse().unwrap();
+            let name = parts.next().unwrap().as_bytes();
+            let value = parts.next().unwrap_or("").as_bytes();
+            assert_eq!(
+                decoder.get(index),
+                Some((name.to_vec(), value.to_vec())),
+                "static table entry {}",
+                index
+            );
+        }
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        let digits = s
+            .bytes()
+            .filter(|b| !b.is_ascii_whitespace())
+            .collect::<Vec<_>>();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    fn headers(fields: &[(&str, &str)]) -> Vec<Header> {
+        fields
+            .iter()
+            .map(|&(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            .collect()
+    }
+
+    /// A header block in hex, the fields it holds and the size of the dynamic table after it
+    type Example<'a> = (&'a str, &'a [(&'a str, &'a str)], usize);
+
+    /// Decode each block in turn with the same decoder, checking the fields and
+    /// the size of the dynamic table after each
+    fn check_sequence(decoder: &mut Decoder, blocks: &[Example]) {
+        for (i, &(block, fields, table_size)) in blocks.iter().enumerate() {
+            let (decoded, truncated) = decoder.decode(&hex(block), usize::MAX).unwrap();
+            assert!(!truncated);
+            assert_eq!(decoded, headers(fields), "block {}", i + 1);
+            assert_eq!(decoder.size, table_size, "table size after block {}", i + 1);
+        }
+    }
+
+    // RFC 7541 Appendix C.2
+    #[test]
+    fn literal_fields() {
+        let mut decoder = Decoder::new();
+        check_sequence(
+            &mut decoder,
+            &[(
+                "400a 6375 7374 6f6d 2d6b 6579 0d63 7573 746f 6d2d 6865 6164 6572",
+                &[("custom-key", "custom-header")],
+                55,
+            )],
+        );
+        let mut decoder = Decoder::new();
+        check_sequence(
+            &mut decoder,
+            &[(
+                "040c 2f73 616d 706c 652f 7061 7468",
+                &[(":path", "/sample/path")],
+                0,
+            )],
+        );
+        let mut decoder = Decoder::new();
+        check_sequence(
+            &mut decoder,
+            &[(
+                "1008 7061 7373 776f 7264 0673 6563 7265 74",
+                &[("password", "secret")],
+                0,
+            )],
+        );
+        let mut decoder = Decoder::new();
+        check_sequence(&mut decoder, &[("82", &[(":method", "GET")], 0)]);
+    }
+
+    const REQUEST_1: &[(&str, &str)] = &[
+        (":method", "GET"),
+        (":scheme", "http"),
+        (":path", "/"),
+        (":authority", "www.example.com"),
+    ];
+    const REQUEST_2: &[(&str, &str)] = &[
+        (":method", "GET"),
+        (":scheme", "http"),
+        (":path", "/"),
+        (":authority", "www.example.com"),
+        ("cache-control", "no-cache"),
+    ];
+    const REQUEST_3: &[(&str, &str)] = &[
+        (":method", "GET"),
+        (":scheme", "https"),
+        (":path", "/index.html"),
+        (":authority", "www.example.com"),
+        ("custom-key", "custom-value"),
+    ];
+
+    // RFC 7541 Appendix C.3
+    #[test]
+    fn requests() {
+        check_sequence(
+            &mut Decoder::new(),
+            &[
+                (
+                    "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+                    REQUEST_1,
+                    57,
+                ),
+                ("8286 84be 5808 6e6f 2d63 6163 6865", REQUEST_2, 110),
+                (
+                    "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+                    REQUEST_3,
+                    164,
+                ),
+            ],
+        );
+    }
+
+    // RFC 7541 Appendix C.4
+    #[test]
+    fn requests_huffman() {
+        check_sequence(
+            &mut Decoder::new(),
+            &[
+                ("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff", REQUEST_1, 57),
+                ("8286 84be 5886 a8eb 1064 9cbf", REQUEST_2, 110),
+                (
+                    "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+                    REQUEST_3,
+                    164,
+                ),
+            ],
+        );
+    }
+
+    const RESPONSE_1: &[(&str, &str)] = &[
+        (":status", "302"),
+        ("cache-control", "private"),
+        ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+        ("location", "https://www.example.com"),
+    ];
+    const RESPONSE_2: &[(&str, &str)] = &[
+        (":status", "307"),
+        ("cache-control", "private"),
+        ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+        ("location", "https://www.example.com"),
+    ];
+    const RESPONSE_3: &[(&str, &str)] = &[
+        (":status", "200"),
+        ("cache-control", "private"),
+        ("date", "Mon, 21 Oct 2013 20:13:22 GMT"),
+        ("location", "https://www.example.com"),
+        ("content-encoding", "gzip"),
+        (
+            "set-cookie",
+            "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1",
+        ),
+    ];
+
+    /// A decoder whose dynamic table is limited to 256 bytes, as in the response examples
+    fn small_table_decoder() -> Decoder {
+        let mut decoder = Decoder::new();
+        decoder.decode(&[0x3f, 0xe1, 0x01], usize::MAX).unwrap();
+        assert_eq!(decoder.max_size, 256);
+        decoder
+    }
+
+    // RFC 7541 Appendix C.5
+    #[test]
+    fn responses_with_eviction() {
+        check_sequence(
+            &mut small_table_decoder(),
+            &[
+                (
+                    "4803 3330 3258 0770 7269 7661 7465 611d 4d6f 6e2c 2032 3120 4f63 7420 3230
+                     3133 2032 303a 3133 3a32 3120 474d 546e 1768 7474 7073 3a2f 2f77 7777 2e65
+                     7861 6d70 6c65 2e63 6f6d",
+                    RESPONSE_1,
+                    222,
+                ),
+                ("4803 3330 37c1 c0bf", RESPONSE_2, 222),
+                (
+                    "88c1 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a 3133 3a32 3220
+                     474d 54c0 5a04 677a 6970 7738 666f 6f3d 4153 444a 4b48 514b 425a 584f 5157
+                     454f 5049 5541 5851 5745 4f49 553b 206d 6178 2d61 6765 3d33 3630 303b 2076
+                     6572 7369 6f6e 3d31",
+                    RESPONSE_3,
+                    215,
+                ),
+            ],
+        );
+    }
+
+    // RFC 7541 Appendix C.6
+    #[test]
+    fn responses_huffman_with_eviction() {
+        check_sequence(
+            &mut small_table_decoder(),
+            &[
+                (
+                    "4882 6402 5885 aec3 771a 4b61 96d0 7abe 9410 54d4 44a8 2005 9504 0b81 66e0
+                     82a6 2d1b ff6e 919d 29ad 1718 63c7 8f0b 97c8 e9ae 82ae 43d3",
+                    RESPONSE_1,
+                    222,
+                ),
+                ("4883 640e ffc1 c0bf", RESPONSE_2, 222),
+                (
+                    "88c1 6196 d07a be94 1054 d444 a820 0595 040b 8166 e084 a62d 1bff c05a 839b
+                     d9ab 77ad 94e7 821d d7f2 e6c7 b335 dfdf cd5b 3960 d5af 2708 7f36 72c1 ab27
+                     0fb5 291f 9587 3160 65c0 03ed 4ee5 b106 3d50 07",
+                    RESPONSE_3,
+                    215,
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn malformed_blocks() {
+        // index 0, an index past the tables, a table size update over the limit,
+        // a string longer than the block, EOS in a Huffman string
+        for block in &["80", "ff00", "3fe21f", "0003616263", "0082ffff"] {
+            assert!(
+                Decoder::new().decode(&hex(block), usize::MAX).is_none(),
+                "{}",
+                block
+            );
+        }
+    }
+
+    #[test]
+    fn encoded_fields_decode() {
+        let fields = headers(&[
+            (":status", "200"),
+            (":status", "418"),
+            ("content-type", "text/plain"),
+            ("x-custom", "value"),
+            ("accept-charset", ""),
+        ]);
+        let mut block = Vec::new();
+        encode(fields.iter().map(|(n, v)| (&n[..], &v[..])), &mut block);
+        // fully indexed static entries take a single byte
+        assert_eq!(block[0], 0x88);
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.decode(&block, usize::MAX), Some((fields, false)));
+        // nothing was added to the dynamic table
+        assert_eq!(decoder.size, 0);
+    }
+
+    #[test]
+    fn header_list_size_limit() {
+        let (decoded, truncated) = Decoder::new()
+            .decode(
+                &hex("8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d"),
+                90,
+            )
+            .unwrap();
+        assert!(truncated);
+        assert_eq!(decoded, headers(&REQUEST_1[..2]));
+    }
+}
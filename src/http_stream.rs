@@ -3,10 +3,7 @@ use httparse;
 use std;
 
 use errors::*;
-use RequestHead;
-
-// relevant: https://stackoverflow.com/questions/686217/maximum-on-http-header-values#686243
-const MAX_HEADER_SIZE: usize = 4096;
+use {Limits, RequestHead};
 
 /// Http reader/parser for incrementally reading a request and
 /// parsing its headers
@@ -15,12 +12,25 @@ pub(crate) struct HttpStreamReader {
     pub headers_length: usize,
     header_lines: usize,
     headers_complete: bool,
-    header_cursor: usize,
     request: Option<RequestHead>,
+    /// Version of the request, once its request line is parsed
+    version: Option<http::Version>,
 
     content_length: usize,
-    body_bytes_read: usize,
+    chunked: Option<ChunkedDecoder>,
     body_complete: bool,
+
+    /// Bytes received past the end of the current request. These are the start
+    /// of the next (pipelined) request on the same connection
+    surplus: Vec<u8>,
+
+    /// Whether the connection should stay open once this request is answered
+    pub keep_alive: bool,
+
+    /// Set when the client waits for a `100 Continue` before sending the body
+    expects_continue: bool,
+
+    limits: Limits,
 }
 impl std::default::Default for HttpStreamReader {
     fn default() -> HttpStreamReader {
@@ -29,22 +39,82 @@ impl std::default::Default for HttpStreamReader {
             header_lines: 0,
             headers_length: 0,
             headers_complete: false,
-            header_cursor: 0,
             request: None,
+            version: None,
             content_length: 0,
-            body_bytes_read: 0,
+            chunked: None,
             body_complete: false,
+            surplus: Vec::new(),
+            keep_alive: true,
+            expects_continue: false,
+            limits: Limits::default(),
         }
     }
 }
 impl HttpStreamReader {
-    pub fn new() -> Self {
+    pub fn new(limits: Limits) -> Self {
         Self {
             read_buf: Vec::with_capacity(1024),
+            limits,
             ..Self::default()
         }
     }
 
+    /// Prepare to read the next request on a persistent connection.
+    ///
+    /// Any bytes received past the end of the previous request belong to the
+    /// next request and are carried over into the fresh buffer.
+    pub fn reset(&mut self) {
+        let mut pending = std::mem::take(&mut self.surplus);
+        pending.extend_from_slice(&self.read_buf);
+        *self = Self::new(self.limits);
+        self.receive_chunk(&pending);
+    }
+
+    /// Whether some bytes of a request have been received already
+    pub fn has_partial_request(&self) -> bool {
+        !self.read_buf.is_empty() || !self.surplus.is_empty()
+    }
+
+    /// Whether the header block of the current request has been parsed
+    pub fn headers_complete(&self) -> bool {
+        self.headers_complete
+    }
+
+    /// Version of the current request, `None` until its header block is parsed,
+    /// or turned down for its size
+    pub fn version(&self) -> Option<http::Version> {
+        self.version
+    }
+
+    /// Whether more bytes should be read for the current request. Reading stops once
+    /// enough is buffered for any request within the limits, so a client can't make
+    /// us buffer more than that before the request is rejected
+    pub fn wants_more(&self) -> bool {
+        match self.chunked {
+            // the decoded body is checked against the limits as it grows, only
+            // count what's left to decode, e.g. a backlog of small chunks
+            Some(ref decoder) if !self.body_complete => {
+                self.read_buf.len() - decoder.cursor
+                    <= self.limits.max_header_bytes + self.limits.max_body_bytes
+            }
+            _ => {
+                self.read_buf.len() + self.surplus.len()
+                    <= self.limits.max_header_bytes + self.limits.max_body_bytes
+            }
+        }
+    }
+
+    /// The head of the request, once, if the client is waiting for a `100 Continue`
+    /// before sending its body
+    pub fn take_expect_continue(&mut self) -> Option<&RequestHead> {
+        if !self.expects_continue || self.body_complete {
+            return None;
+        }
+        self.expects_continue = false;
+        self.request.as_ref()
+    }
+
     /// Save a new chunk of bytes
     pub fn receive_chunk(&mut self, chunk: &[u8]) -> usize {
         self.read_buf.extend_from_slice(chunk);
@@ -55,33 +125,39 @@ impl HttpStreamReader {
     ///
     /// After headers are parsed, collect the remaining body bytes.
     /// After Content-length bytes are parsed, return the parsed request headers.
+    /// Chunked bodies are decoded as they arrive so `read_buf` ends up holding the
+    /// plain body right after the headers, same as with a Content-length.
+    /// Bytes following the body are held back for the next request, see `reset`.
     pub fn try_build_request(&mut self) -> Result<Option<RequestHead>> {
         if !self.headers_complete {
             // check if we've got enough data to successfully parse the request
             const R: u8 = b'\r';
             const N: u8 = b'\n';
-            // slide back 3 spaces in case the previous chunk ended with "\r\n\r"
-            let cursor = if self.header_cursor >= 3 {
-                self.header_cursor - 3
-            } else {
-                0
-            };
-            let mut headers_length = if self.headers_length < 4 {
-                3
-            } else {
-                self.headers_length - 3
-            };
+            // resume with the first 4 byte window that wasn't complete yet, in case
+            // the previous chunk ended with "\r\n\r". Each window scanned extends
+            // the headers up to its end
+            let cursor = self.headers_length.saturating_sub(3);
+            let mut headers_length = cursor + 3;
             let data = &self.read_buf[cursor..];
             for window in data.windows(4) {
                 if window.len() < 4 {
                     break;
                 }
                 headers_length += 1;
-                if headers_length > MAX_HEADER_SIZE {
+                if headers_length > self.limits.max_header_bytes {
+                    if self.header_lines == 0 {
+                        // still on the request line, blame its target
+                        bail_fmt!(
+                            ErrorKind::UriTooLong,
+                            "Request line is longer than limit: {}",
+                            self.limits.max_header_bytes
+                        )
+                    }
+                    self.version = request_line_version(&self.read_buf);
                     bail_fmt!(
                         ErrorKind::RequestHeadersTooLarge,
                         "Headers are larger than limit: {}",
-                        MAX_HEADER_SIZE
+                        self.limits.max_header_bytes
                     )
                 }
                 if window == [R, N, R, N] {
@@ -90,10 +166,19 @@ impl HttpStreamReader {
                 }
                 if window[..2] == [R, N] {
                     self.header_lines += 1;
+                    // the request line's CRLF is counted, the last header's is part
+                    // of the terminating CRLFCRLF, so this is the number of headers
+                    if self.header_lines > self.limits.max_headers {
+                        self.version = request_line_version(&self.read_buf);
+                        bail_fmt!(
+                            ErrorKind::RequestHeadersTooLarge,
+                            "More headers than limit: {}",
+                            self.limits.max_headers
+                        )
+                    }
                 }
             }
             self.headers_length = headers_length;
-            self.header_cursor = headers_length - 1;
 
             if self.headers_complete {
                 debug!(
@@ -102,10 +187,9 @@ impl HttpStreamReader {
                     std::str::from_utf8(&self.read_buf[..self.headers_length])
                 );
                 // account for body contents that may have come in with this final headers read
-                self.body_bytes_read += self.read_buf.len() - self.headers_length;
                 debug!(
                     "trailing body bytes read: {}, {:?}",
-                    self.body_bytes_read,
+                    self.read_buf.len() - self.headers_length,
                     std::str::from_utf8(&self.read_buf[self.headers_length..])
                 );
             }
@@ -122,6 +206,13 @@ impl HttpStreamReader {
             let header_bytes = &self.read_buf[..self.headers_length];
             let status = match req.parse(header_bytes) {
                 Ok(status) => status,
+                Err(httparse::Error::Version) => {
+                    bail_fmt!(
+                        ErrorKind::UnsupportedVersion,
+                        "Unsupported http version: {:?}",
+                        std::str::from_utf8(&header_bytes[..header_bytes.len().min(64)])
+                    );
+                }
                 Err(e) => {
                     bail_fmt!(
                         ErrorKind::MalformedHttpRequest,
@@ -138,18 +229,86 @@ impl HttpStreamReader {
                 );
             }
             debug_assert!(self.headers_length == status.unwrap());
+            let path = req.path.unwrap();
+            if path.len() > self.limits.max_uri_length {
+                bail_fmt!(
+                    ErrorKind::UriTooLong,
+                    "URI is longer than limit: {}",
+                    self.limits.max_uri_length
+                )
+            }
+
+            // httparse only accepts HTTP/1.x, anything else fails above
+            let version = match req.version {
+                Some(0) => http::Version::HTTP_10,
+                Some(1) => http::Version::HTTP_11,
+                version => bail_fmt!(
+                    ErrorKind::UnsupportedVersion,
+                    "Unsupported http version: 1.{:?}",
+                    version
+                ),
+            };
+            self.version = Some(version);
 
             // HTTP parsing success. Build an `http::Request`
             let mut request = http::Request::builder()
                 .method(req.method.unwrap())
-                .uri(req.path.unwrap());
-            // TODO: http::Request expects consts and not strs. Defaults to HTTP/1.1 for now
-            // request.version(req.version.unwrap());
+                .uri(path)
+                .version(version);
             for header in req.headers {
                 request = request.header(header.name, header.value);
             }
-            // use an empty body as a placeholder while we continue to read the request body
-            let request = request.body(()).unwrap();
+            // use an empty body as a placeholder while we continue to read the request body.
+            // httparse lets through targets, and header values, that `http` turns down
+            let request = request.body(()).chain_err(|| {
+                format_err!(
+                    ErrorKind::MalformedHttpRequest,
+                    "Malformed http request: {:?}",
+                    std::str::from_utf8(header_bytes)
+                )
+            })?;
+
+            // HTTP/1.1 connections are persistent unless the client asks otherwise,
+            // HTTP/1.0 connections are only kept open when explicitly requested
+            self.keep_alive = match version {
+                http::Version::HTTP_10 => has_connection_option(&request, "keep-alive"),
+                _ => !has_connection_option(&request, "close"),
+            };
+
+            // framing headers sent more than once can be read differently by a proxy
+            // in front of us, another way of smuggling requests
+            for name in &[
+                http::header::TRANSFER_ENCODING,
+                http::header::CONTENT_LENGTH,
+            ] {
+                if request.headers().get_all(name).iter().count() > 1 {
+                    bail_fmt!(ErrorKind::MalformedHttpRequest, "Multiple {} headers", name);
+                }
+            }
+
+            // check for a chunked transfer-encoding, which takes over from any content-length
+            let transfer_encoding = request
+                .headers()
+                .get(http::header::TRANSFER_ENCODING)
+                .map(|val| val.to_str().unwrap_or("").trim().to_string());
+            if let Some(transfer_encoding) = transfer_encoding {
+                if !transfer_encoding.eq_ignore_ascii_case("chunked") {
+                    bail_fmt!(
+                        ErrorKind::UnsupportedTransferCoding,
+                        "Unsupported transfer-encoding: {}",
+                        transfer_encoding
+                    );
+                }
+                // a request stating both lengths is ambiguous, and the classic
+                // way of smuggling requests past a proxy
+                if request.headers().contains_key(http::header::CONTENT_LENGTH) {
+                    bail_fmt!(
+                        ErrorKind::MalformedHttpRequest,
+                        "Both transfer-encoding and content-length specified"
+                    );
+                }
+                self.chunked = Some(ChunkedDecoder::new(self.headers_length, self.limits));
+            }
 
             // check for a content-length
             // at this point the headers have been parsed by httparse, so they should all be
@@ -160,6 +319,14 @@ impl HttpStreamReader {
                     .get(http::header::CONTENT_LENGTH)
                     .and_then(|val| val.to_str().ok())
                     .unwrap_or("0");
+                // only digits, `parse` would take a leading `+` as well
+                if !content_length.bytes().all(|b| b.is_ascii_digit()) {
+                    bail_fmt!(
+                        ErrorKind::MalformedHttpRequest,
+                        "Invalid content-length: {}",
+                        content_length
+                    );
+                }
                 content_length.parse::<usize>().chain_err(|| {
                     format_err!(
                         ErrorKind::MalformedHttpRequest,
@@ -168,22 +335,463 @@ impl HttpStreamReader {
                     )
                 })?
             };
-            self.request = Some(request)
-        }
-
-        if !self.body_complete {
-            if self.body_bytes_read > self.content_length {
+            // the only expectation there is, HTTP/1.0 clients can't expect anything
+            if let Some(expect) = request.headers().get(http::header::EXPECT) {
+                if !expect.as_bytes().eq_ignore_ascii_case(b"100-continue") {
+                    bail_fmt!(
+                        ErrorKind::ExpectationFailed,
+                        "Unsupported expectation: {:?}",
+                        expect
+                    );
+                }
+                self.expects_continue = version == http::Version::HTTP_11
+                    && (self.chunked.is_some() || self.content_length > 0);
+            }
+            if self.content_length > self.limits.max_body_bytes {
                 bail_fmt!(
                     ErrorKind::RequestBodyTooLarge,
-                    "Body is larger than stated content-length: {}",
-                    self.content_length
-                );
+                    "Content-length {} is larger than limit: {}",
+                    self.content_length,
+                    self.limits.max_body_bytes
+                )
             }
-            self.body_complete = self.body_bytes_read == self.content_length;
+            self.request = Some(request)
         }
+
         if !self.body_complete {
-            return Ok(None);
+            let request_end = match self.chunked {
+                Some(ref mut decoder) => {
+                    if !decoder.decode(&mut self.read_buf)? {
+                        return Ok(None);
+                    }
+                    self.content_length = decoder.decoded_end - self.headers_length;
+                    if let Some(ref mut request) = self.request {
+                        let trailers = std::mem::take(&mut decoder.trailers);
+                        request.extensions_mut().insert(Trailers(trailers));
+                    }
+                    decoder.decoded_end
+                }
+                None => {
+                    let body_bytes_read = self.read_buf.len() - self.headers_length;
+                    if body_bytes_read < self.content_length {
+                        return Ok(None);
+                    }
+                    self.headers_length + self.content_length
+                }
+            };
+            self.body_complete = true;
+            // anything past the end of the body is the start of the next request,
+            // keep it aside so `read_buf` only holds the bytes of this request
+            self.surplus = self.read_buf.split_off(request_end);
+            if !self.surplus.is_empty() {
+                debug!("{} pipelined bytes following request", self.surplus.len());
+            }
         }
         Ok(self.request.take())
     }
 }
+
+/// Trailer fields sent after a chunked request body, stored in the request extensions
+pub(crate) struct Trailers(pub http::HeaderMap);
+
+/// Position within a chunked body
+enum ChunkedState {
+    /// Expecting a chunk-size line, possibly with chunk extensions
+    Size,
+    /// Expecting this many more bytes of chunk data
+    Data(usize),
+    /// Expecting the CRLF that closes a chunk's data
+    DataEnd,
+    /// Expecting trailer fields or the empty line ending the body
+    Trailers,
+}
+
+/// Incremental decoder for `Transfer-Encoding: chunked` request bodies.
+///
+/// The body is decoded in place: chunk data is moved down in the read buffer
+/// so the decoded body directly follows the headers, while the raw bytes that
+/// haven't been decoded yet stay after it. The chunk framing is dropped as it's
+/// decoded, so the buffer never holds more than the body and what's left to decode.
+struct ChunkedDecoder {
+    state: ChunkedState,
+    /// Position of the next raw byte to decode
+    cursor: usize,
+    /// End of the decoded body
+    decoded_end: usize,
+    trailers: http::HeaderMap,
+    trailers_size: usize,
+    body_start: usize,
+    limits: Limits,
+}
+impl ChunkedDecoder {
+    fn new(body_start: usize, limits: Limits) -> Self {
+        Self {
+            state: ChunkedState::Size,
+            cursor: body_start,
+            decoded_end: body_start,
+            trailers: http::HeaderMap::new(),
+            trailers_size: 0,
+            body_start,
+            limits,
+        }
+    }
+
+    /// Decode as much of `buf` as has been received, dropping the decoded framing.
+    /// Returns `true` once the last chunk and the trailers have been read
+    fn decode(&mut self, buf: &mut Vec<u8>) -> Result<bool> {
+        let complete = self.decode_buffered(buf)?;
+        buf.drain(self.decoded_end..self.cursor).for_each(drop);
+        self.cursor = self.decoded_end;
+        Ok(complete)
+    }
+
+    fn decode_buffered(&mut self, buf: &mut [u8]) -> Result<bool> {
+        loop {
+            match self.state {
+                ChunkedState::Size => {
+                    let line_end = match self.next_line(buf)? {
+                        Some(end) => end,
+                        None => return Ok(false),
+                    };
+                    let size = {
+                        let line = &buf[self.cursor..line_end];
+                        // chunk extensions are allowed after the size, we don't use any of them
+                        let size = match line.iter().position(|b| *b == b';') {
+                            Some(ext_start) => &line[..ext_start],
+                            None => line,
+                        };
+                        std::str::from_utf8(size)
+                            .ok()
+                            .map(|size| size.trim())
+                            .filter(|size| !size.is_empty() && size.len() <= 16)
+                            .and_then(|size| usize::from_str_radix(size, 16).ok())
+                            .ok_or_else(|| {
+                                format_err!(
+                                    ErrorKind::MalformedHttpRequest,
+                                    "Invalid chunk size: {:?}",
+                                    std::str::from_utf8(line)
+                                )
+                            })?
+                    };
+                    if size > self.limits.max_body_bytes - (self.decoded_end - self.body_start) {
+                        bail_fmt!(
+                            ErrorKind::RequestBodyTooLarge,
+                            "Chunked body is larger than limit: {}",
+                            self.limits.max_body_bytes
+                        )
+                    }
+                    self.cursor = line_end + 2;
+                    self.state = if size == 0 {
+                        ChunkedState::Trailers
+                    } else {
+                        ChunkedState::Data(size)
+                    };
+                }
+                ChunkedState::Data(remaining) => {
+                    let available = std::cmp::min(remaining, buf.len() - self.cursor);
+                    if available == 0 {
+                        return Ok(false);
+                    }
+                    buf.copy_within(self.cursor..self.cursor + available, self.decoded_end);
+                    self.cursor += available;
+                    self.decoded_end += available;
+                    self.state = if remaining == available {
+                        ChunkedState::DataEnd
+                    } else {
+                        ChunkedState::Data(remaining - available)
+                    };
+                }
+                ChunkedState::DataEnd => {
+                    if buf.len() < self.cursor + 2 {
+                        return Ok(false);
+                    }
+                    if buf[self.cursor..self.cursor + 2] != [b'\r', b'\n'] {
+                        bail_fmt!(
+                            ErrorKind::MalformedHttpRequest,
+                            "Chunk data not terminated by CRLF"
+                        );
+                    }
+                    self.cursor += 2;
+                    self.state = ChunkedState::Size;
+                }
+                ChunkedState::Trailers => {
+                    let line_end = match self.next_line(buf)? {
+                        Some(end) => end,
+                        None => return Ok(false),
+                    };
+                    if line_end == self.cursor {
+                        // empty line, the body is complete
+                        self.cursor += 2;
+                        return Ok(true);
+                    }
+                    self.trailers_size += line_end + 2 - self.cursor;
+                    if self.trailers_size > self.limits.max_header_bytes {
+                        bail_fmt!(
+                            ErrorKind::RequestHeadersTooLarge,
+                            "Trailers are larger than limit: {}",
+                            self.limits.max_header_bytes
+                        )
+                    }
+                    self.parse_trailer(&buf[self.cursor..line_end])?;
+                    self.cursor = line_end + 2;
+                }
+            }
+        }
+    }
+
+    /// Find the CRLF ending the line starting at `cursor`
+    fn next_line(&self, buf: &[u8]) -> Result<Option<usize>> {
+        let line = &buf[self.cursor..];
+        match line.windows(2).position(|window| window == b"\r\n") {
+            Some(len) => Ok(Some(self.cursor + len)),
+            None if line.len() > self.limits.max_header_bytes => bail_fmt!(
+                ErrorKind::MalformedHttpRequest,
+                "Chunk line is longer than limit: {}",
+                self.limits.max_header_bytes
+            ),
+            None => Ok(None),
+        }
+    }
+
+    fn parse_trailer(&mut self, line: &[u8]) -> Result<()> {
+        let invalid = || {
+            format_err!(
+                ErrorKind::MalformedHttpRequest,
+                "Invalid trailer: {:?}",
+                std::str::from_utf8(line)
+            )
+        };
+        let colon = line.iter().position(|b| *b == b':').ok_or_else(invalid)?;
+        let name = http::header::HeaderName::from_bytes(&line[..colon]).map_err(|_| invalid())?;
+        let value = std::str::from_utf8(&line[colon + 1..]).map_err(|_| invalid())?;
+        let value = http::header::HeaderValue::from_str(value.trim()).map_err(|_| invalid())?;
+        self.trailers.append(name, value);
+        Ok(())
+    }
+}
+
+/// Version at the end of the request line at the start of `buf`, to answer a
+/// request whose header block can't be parsed in kind
+fn request_line_version(buf: &[u8]) -> Option<http::Version> {
+    let end = buf.windows(2).position(|window| window == b"\r\n")?;
+    let line = &buf[..end];
+    if line.ends_with(b" HTTP/1.0") {
+        Some(http::Version::HTTP_10)
+    } else if line.ends_with(b" HTTP/1.1") {
+        Some(http::Version::HTTP_11)
+    } else {
+        None
+    }
+}
+
+/// Check if the `Connection` header(s) of a request contain the given option
+pub(crate) fn has_connection_option(request: &RequestHead, option: &str) -> bool {
+    request
+        .headers()
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .filter_map(|val| val.to_str().ok())
+        .flat_map(|val| val.split(','))
+        .any(|opt| opt.trim().eq_ignore_ascii_case(option))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `input` to a reader the way a connection does: `step` bytes at a time
+    /// while it wants more, trying to build the request in between
+    fn read_request(
+        reader: &mut HttpStreamReader,
+        input: &[u8],
+        step: usize,
+    ) -> Result<Option<RequestHead>> {
+        let mut pos = 0;
+        loop {
+            while reader.wants_more() && pos < input.len() {
+                let end = std::cmp::min(pos + step, input.len());
+                reader.receive_chunk(&input[pos..end]);
+                pos = end;
+            }
+            if let Some(request) = reader.try_build_request()? {
+                return Ok(Some(request));
+            }
+            if pos == input.len() || !reader.wants_more() {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn body(reader: &HttpStreamReader) -> &[u8] {
+        &reader.read_buf[reader.headers_length..]
+    }
+
+    fn is_malformed(input: &[u8]) -> bool {
+        let mut reader = HttpStreamReader::new(Limits::default());
+        matches!(
+            read_request(&mut reader, input, input.len()),
+            Err(Error(ErrorKind::MalformedHttpRequest(_), _))
+        )
+    }
+
+    #[test]
+    fn ambiguous_framing_is_rejected() {
+        assert!(is_malformed(
+            b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\nabcd"
+        ));
+        assert!(is_malformed(
+            b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 3\r\n\r\nabc"
+        ));
+        assert!(is_malformed(
+            b"POST / HTTP/1.1\r\nContent-Length: 3, 3\r\n\r\nabc"
+        ));
+        assert!(is_malformed(
+            b"POST / HTTP/1.1\r\nContent-Length: +3\r\n\r\nabc"
+        ));
+        assert!(is_malformed(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n"
+        ));
+        assert!(is_malformed(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n0\r\n\r\n"
+        ));
+        assert!(!is_malformed(
+            b"POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc"
+        ));
+    }
+
+    #[test]
+    fn invalid_target_is_rejected() {
+        // httparse accepts it, `http::Uri` doesn't
+        assert!(is_malformed(b"GET http://[::1 HTTP/1.1\r\n\r\n"));
+        assert!(is_malformed(b"GET http://a:b:c/ HTTP/1.1\r\n\r\n"));
+        assert!(is_malformed(b"GET http:// HTTP/1.1\r\n\r\n"));
+    }
+
+    #[test]
+    fn version_known_when_rejecting() {
+        let mut reader = HttpStreamReader::new(Limits::default());
+        let input = b"POST / HTTP/1.0\r\nContent-Length: 99999999999\r\n\r\n";
+        assert!(read_request(&mut reader, input, input.len()).is_err());
+        assert_eq!(reader.version(), Some(http::Version::HTTP_10));
+
+        let mut reader = HttpStreamReader::new(Limits::default());
+        let mut input = b"GET / HTTP/1.0\r\nX: ".to_vec();
+        input.extend_from_slice(&[b'a'; 5000]);
+        assert!(read_request(&mut reader, &input, input.len()).is_err());
+        assert_eq!(reader.version(), Some(http::Version::HTTP_10));
+
+        let mut reader = HttpStreamReader::new(Limits::default());
+        let input = b"POST / HTTP/1.0\r\n";
+        assert!(read_request(&mut reader, input, input.len())
+            .unwrap()
+            .is_none());
+        assert_eq!(reader.version(), None);
+    }
+
+    #[test]
+    fn many_small_chunks_within_limits() {
+        let limits = Limits {
+            max_header_bytes: 256,
+            max_body_bytes: 1024,
+            ..Limits::default()
+        };
+        let mut input = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        // 6 bytes of framing and data per body byte, far more than the limits
+        for _ in 0..1000 {
+            input.extend_from_slice(b"1\r\na\r\n");
+        }
+        input.extend_from_slice(b"0\r\n\r\n");
+        let mut reader = HttpStreamReader::new(limits);
+        let request = read_request(&mut reader, &input, 256).unwrap();
+        assert!(request.is_some());
+        assert_eq!(body(&reader), &[b'a'; 1000][..]);
+    }
+
+    const CHUNKED_HEAD: &[u8] = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+    /// Decode `body` sent after a chunked request head, `step` bytes at a time
+    fn chunked(body: &[u8], step: usize, limits: Limits) -> Result<(Vec<u8>, RequestHead)> {
+        let mut input = CHUNKED_HEAD.to_vec();
+        input.extend_from_slice(body);
+        let mut reader = HttpStreamReader::new(limits);
+        let request = read_request(&mut reader, &input, step)?.expect("complete request");
+        Ok((self::body(&reader).to_vec(), request))
+    }
+
+    #[test]
+    fn chunked_split_anywhere() {
+        let body = b"5\r\nhello\r\n1\r\n \r\na\r\n0123456789\r\n0\r\n\r\n";
+        for step in 1..body.len() + CHUNKED_HEAD.len() {
+            let (decoded, _) = chunked(body, step, Limits::default()).unwrap();
+            assert_eq!(decoded, b"hello 0123456789", "step {}", step);
+        }
+    }
+
+    #[test]
+    fn chunked_extensions_and_hex_sizes() {
+        let body = b"A;name=value\r\n0123456789\r\n1f ; ext\r\n0123456789abcdefghijklmnopqrstu\r\n0;last\r\n\r\n";
+        let (decoded, _) = chunked(body, 7, Limits::default()).unwrap();
+        assert_eq!(decoded, &b"01234567890123456789abcdefghijklmnopqrstu"[..]);
+    }
+
+    #[test]
+    fn chunked_trailers() {
+        let body = b"3\r\nabc\r\n0\r\nChecksum: 1234\r\nX-Other:  two \r\n\r\n";
+        let (decoded, request) = chunked(body, 5, Limits::default()).unwrap();
+        assert_eq!(decoded, b"abc");
+        let trailers = &request.extensions().get::<Trailers>().unwrap().0;
+        assert_eq!(trailers["checksum"], "1234");
+        assert_eq!(trailers["x-other"], "two");
+    }
+
+    #[test]
+    fn chunked_pipelined_request_kept() {
+        let mut input = CHUNKED_HEAD.to_vec();
+        input.extend_from_slice(b"2\r\nok\r\n0\r\n\r\nGET /next HTTP/1.1\r\n\r\n");
+        let mut reader = HttpStreamReader::new(Limits::default());
+        assert!(read_request(&mut reader, &input, input.len())
+            .unwrap()
+            .is_some());
+        assert_eq!(body(&reader), b"ok");
+        reader.reset();
+        let next = reader.try_build_request().unwrap().unwrap();
+        assert_eq!(next.uri().path(), "/next");
+    }
+
+    #[test]
+    fn chunked_oversize_rejected() {
+        let limits = Limits {
+            max_body_bytes: 8,
+            ..Limits::default()
+        };
+        let too_large = |body: &[u8]| {
+            matches!(
+                chunked(body, 4, limits),
+                Err(Error(ErrorKind::RequestBodyTooLarge(_), _))
+            )
+        };
+        assert!(!too_large(b"8\r\n01234567\r\n0\r\n\r\n"));
+        // a single chunk announcing more, before any of it is received
+        assert!(too_large(b"9\r\n"));
+        // chunks adding up to more
+        assert!(too_large(b"5\r\n01234\r\n4\r\n0123\r\n0\r\n\r\n"));
+        // sizes that would overflow
+        assert!(too_large(b"ffffffffffffffff\r\n"));
+    }
+
+    #[test]
+    fn chunked_malformed_rejected() {
+        for body in &[
+            &b"x\r\nabc\r\n0\r\n\r\n"[..],
+            b"\r\n",
+            b"3\r\nabcd\r\n0\r\n\r\n",
+            b"10000000000000000\r\n",
+            b"3\r\nabc\r\n0\r\nno colon\r\n\r\n",
+        ] {
+            match chunked(body, 3, Limits::default()) {
+                Err(Error(ErrorKind::MalformedHttpRequest(_), _)) => {}
+                res => panic!("{:?}: {:?}", std::str::from_utf8(body), res.map(|r| r.0)),
+            }
+        }
+    }
+}
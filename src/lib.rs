@@ -28,13 +28,43 @@ extern crate error_chain;
 extern crate log;
 extern crate http;
 extern crate httparse;
+#[macro_use]
+extern crate lazy_static;
+#[cfg(unix)]
+extern crate libc;
 extern crate mio;
+#[cfg(feature = "tls")]
+extern crate rustls;
+#[cfg(feature = "tls")]
+extern crate rustls_pemfile;
+extern crate sha1_smol;
 extern crate slab;
+#[cfg(unix)]
+extern crate socket2;
 
 #[macro_use]
 mod macros;
+mod body;
+mod broadcast;
+mod connection;
 mod errors;
+mod h2;
+mod hpack;
 mod http_stream;
+mod limits;
+mod pool;
+mod shutdown;
+#[cfg(unix)]
+mod signals;
+mod sse;
+mod timer;
+#[cfg(feature = "tls")]
+mod tls;
+mod transport;
+#[cfg(unix)]
+mod unix;
+mod upgrade;
+mod websocket;
 
 pub use http::header;
 pub use http::method;
@@ -43,15 +73,70 @@ pub use http::status;
 pub use http::uri;
 pub use http::version;
 use mio::net::TcpListener;
-use std::io::{self, Read, Write};
+use std::io::{self, Write};
 
+pub use body::Body;
+pub use broadcast::Broadcast;
+use connection::Connection;
 pub use errors::*;
-use http_stream::HttpStreamReader;
+pub use limits::Limits;
+#[cfg(not(target_os = "wasi"))]
+use pool::WorkerPool;
+use pool::{Dispatch, Inline};
+#[cfg(not(target_os = "wasi"))]
+pub use shutdown::ServerHandle;
+use shutdown::Shutdown;
+#[cfg(unix)]
+use signals::Signals;
+pub use sse::{Event, EventStream};
+use sse::{Wakeups, Watcher};
+use std::sync::Arc;
+#[cfg(not(target_os = "wasi"))]
+use std::thread;
+use std::time::{Duration, Instant};
+use timer::{Timeouts, Timers};
+#[cfg(feature = "tls")]
+pub use tls::TlsInfo;
+#[cfg(feature = "tls")]
+use tls::{TlsConfig, TlsStream};
+#[cfg(feature = "tls")]
+use transport::Transport;
+use transport::{Listener, ListenerIndex};
+#[cfg(unix)]
+pub use unix::PeerCredentials;
+pub use upgrade::Upgraded;
+pub use websocket::{Message, WebSocket, WebSocketSender};
+
+/// Renders the responses to failed requests, see `Server::error_handler`
+type ErrorHandler = std::sync::Arc<dyn Fn(&Error) -> Response<Body> + Send + Sync>;
+
+/// Decides whether a client may send a request body, see `Server::expect_continue`
+type ExpectHandler = std::sync::Arc<dyn Fn(&RequestHead) -> Option<Response<Body>> + Send + Sync>;
+
+/// Accepts WebSocket connections, see `Server::websocket`
+type WebSocketHandler =
+    std::sync::Arc<dyn Fn(&RequestHead) -> Option<websocket::Callback> + Send + Sync>;
 
 /// Re-exported `http::Response` for constructing return responses in handlers
 pub use http::Response;
 use mio::{Interest, Token};
 
+/// Maximum number of pipelined requests answered ahead of the client reading
+/// the responses. Further requests stay buffered until the queue drains
+const MAX_PIPELINED_RESPONSES: usize = 32;
+
+/// Token of the `mio::Waker` used to notify the event loop from other threads
+#[cfg(not(target_os = "wasi"))]
+const WAKER_TOKEN: Token = Token(usize::MAX);
+
+/// Token of the self-pipe receiving shutdown signals
+#[cfg(unix)]
+const SIGNAL_TOKEN: Token = Token(usize::MAX - 1);
+
+/// Bytes of a streamed response body written to a socket before moving on
+/// to other events, so one large download can't starve the other connections
+const STREAM_WRITE_BUDGET: usize = 256 * 1024;
+
 #[cfg(target_os = "wasi")]
 #[cfg(not(windows))]
 fn get_first_listen_fd_listener() -> Option<std::net::TcpListener> {
@@ -70,48 +155,201 @@ fn get_first_listen_fd_listener() -> Option<std::net::TcpListener> {
 }
 
 #[cfg(target_os = "wasi")]
-fn get_tcp_listener(_addr: Option<String>) -> TcpListener {
+fn get_tcp_listener(_addr: Option<String>) -> Result<TcpListener> {
     std::env::var("FD_COUNT").expect("FD_COUNT environment variable unset");
     let stdlistener = get_first_listen_fd_listener().unwrap();
-    stdlistener.set_nonblocking(true).unwrap();
-    TcpListener::from_std(stdlistener)
+    stdlistener.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(stdlistener))
 }
 
 #[cfg(not(target_os = "wasi"))]
-fn get_tcp_listener(addr: Option<String>) -> TcpListener {
-    TcpListener::bind(addr.unwrap().parse().unwrap()).unwrap()
+fn get_tcp_listener(addr: Option<String>) -> Result<TcpListener> {
+    Ok(TcpListener::bind(addr.unwrap().parse()?)?)
+}
+
+/// Where a server listens, see `Server::new`, `Server::unix` and `Server::listen`
+#[derive(Clone, Debug)]
+enum Address {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+    /// The socket passed as file descriptor 3, see `Server::preopened`
+    Preopened,
+}
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Address::Tcp(ref addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Address::Unix(ref path) => write!(f, "unix:{}", path.display()),
+            Address::Preopened => write!(f, "preopened socket FD 3"),
+        }
+    }
+}
+
+/// Bind a listener with `SO_REUSEPORT` set, so several of them can share `addr`
+/// and the kernel spreads incoming connections between them
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn get_reuseport_listener(addr: &str) -> Result<TcpListener> {
+    use socket2::{Domain, Socket, Type};
+
+    let addr: std::net::SocketAddr = addr.parse()?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into()))
+}
+
+/// Pin the current thread to the cpu numbered `cpu`
+#[cfg(target_os = "linux")]
+fn pin_to_cpu(cpu: usize) {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            warn!(
+                "Failed to pin thread to cpu {}: {}",
+                cpu,
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+#[cfg(all(not(target_os = "linux"), not(target_os = "wasi")))]
+fn pin_to_cpu(cpu: usize) {
+    warn!(
+        "Pinning threads to cpus isn't supported, not pinning to {}",
+        cpu
+    );
 }
 
 /// Internal `http::Response` wrapper with helpers for constructing the bytes
 /// that needs to be written back a Stream
 struct ResponseWrapper {
-    inner: http::Response<Vec<u8>>,
+    inner: http::Response<Body>,
     header_data: Vec<u8>,
+    keep_alive: bool,
+    /// Whether a streamed body is framed with `Transfer-Encoding: chunked`
+    chunked: bool,
+    /// Current (framed) chunk of a streamed body being written
+    chunk: Vec<u8>,
+    chunk_written: usize,
+    /// Whether a streamed body has produced its last chunk
+    stream_done: bool,
+    /// Whether only the headers are sent, in response to a `HEAD` request or with
+    /// a status that doesn't allow a body
+    head: bool,
+    /// Set when writing stopped because an event stream has nothing to send yet
+    waiting: bool,
 }
 impl ResponseWrapper {
-    fn new(inner: http::Response<Vec<u8>>) -> Self {
+    fn new(inner: http::Response<Body>) -> Self {
         Self {
             inner,
             header_data: Vec::with_capacity(1024),
+            keep_alive: false,
+            chunked: false,
+            chunk: Vec::new(),
+            chunk_written: 0,
+            stream_done: false,
+            head: false,
+            waiting: false,
         }
     }
 
-    fn serialize_headers(&mut self) {
+    /// An interim `1xx` response, which only consists of its status line
+    fn informational(status: http::StatusCode) -> Self {
+        let mut resp = Self::new(http::Response::new(Body::from(Vec::new())));
+        resp.keep_alive = true;
+        resp.head = true;
+        resp.header_data.extend_from_slice(
+            format!(
+                "HTTP/1.1 {} {}\r\n\r\n",
+                status.as_str(),
+                status.canonical_reason().unwrap_or("Unsupported Status")
+            )
+            .as_bytes(),
+        );
+        resp
+    }
+
+    /// Serialize the status line and headers. `keep_alive` is the client's
+    /// preference, a handler can still force the connection to close by setting
+    /// a `Connection: close` header on its response.
+    ///
+    /// The response uses the `version` of the request. HTTP/1.0 clients don't know
+    /// about chunked transfer-encoding, a streamed body without a content-length
+    /// is sent as is and ends when the connection is closed.
+    ///
+    /// For a `head` request, the headers are the same as with a `GET` request,
+    /// including the content-length, but the body isn't sent.
+    fn serialize_headers(&mut self, keep_alive: bool, version: http::Version, head: bool) {
+        let http_10 = version == http::Version::HTTP_10;
+        let mut close_delimited = false;
+        let status = self.inner.status();
+        let bodiless = status.is_informational()
+            || status == status::StatusCode::NO_CONTENT
+            || status == status::StatusCode::NOT_MODIFIED;
+        self.head = head || bodiless;
         {
-            let body_len = self.inner.body().len();
+            let body_len = self.inner.body().content_length();
             let hdrs = self.inner.headers_mut();
             hdrs.insert(
                 header::SERVER,
                 header::HeaderValue::from_static("mini-http (rust)"),
             );
-            if body_len > 0 {
-                let len = header::HeaderValue::from_str(&body_len.to_string()).unwrap();
-                hdrs.insert(header::CONTENT_LENGTH, len);
+            // clients of a persistent connection rely on the content-length (or the
+            // chunked framing) to know where this response ends, so only skip them
+            // when a body isn't allowed. A `304` keeps the content-length the handler
+            // gave it, that of the representation the client has
+            if !bodiless {
+                match body_len {
+                    // a handler answering `HEAD` itself can set the length of the
+                    // body it would have sent
+                    Some(0) if head && hdrs.contains_key(header::CONTENT_LENGTH) => {}
+                    Some(body_len) => {
+                        let len = header::HeaderValue::from_str(&body_len.to_string()).unwrap();
+                        hdrs.insert(header::CONTENT_LENGTH, len);
+                    }
+                    // a streamed body with a length set by the handler is sent as is
+                    None if hdrs.contains_key(header::CONTENT_LENGTH) => {}
+                    None if http_10 => close_delimited = !head,
+                    None => {
+                        self.chunked = true;
+                        hdrs.insert(
+                            header::TRANSFER_ENCODING,
+                            header::HeaderValue::from_static("chunked"),
+                        );
+                    }
+                }
+            }
+            let handler_close = hdrs
+                .get(header::CONNECTION)
+                .and_then(|val| val.to_str().ok())
+                .map(|val| val.eq_ignore_ascii_case("close"))
+                .unwrap_or(false);
+            self.keep_alive = keep_alive && !handler_close && !close_delimited;
+            let connection = if self.keep_alive {
+                "keep-alive"
+            } else {
+                "close"
+            };
+            // the `Connection: upgrade` of a switch to another protocol is kept
+            if status != status::StatusCode::SWITCHING_PROTOCOLS {
+                hdrs.insert(
+                    header::CONNECTION,
+                    header::HeaderValue::from_static(connection),
+                );
             }
         }
         let status = self.inner.status();
         let s = format!(
-            "HTTP/1.1 {} {}\r\n",
+            "{} {} {}\r\n",
+            if http_10 { "HTTP/1.0" } else { "HTTP/1.1" },
             status.as_str(),
             status.canonical_reason().unwrap_or("Unsupported Status")
         );
@@ -125,9 +363,92 @@ impl ResponseWrapper {
         }
         self.header_data.extend_from_slice(b"\r\n");
     }
+
+    /// Write as much of the serialized headers and body as `stream` accepts,
+    /// continuing from `bytes_written`. Returns `true` once everything is written.
+    ///
+    /// Streamed bodies are pulled one chunk at a time, only when the previous one
+    /// has been written out. After `STREAM_WRITE_BUDGET` bytes this returns so other
+    /// connections get a turn, the stream must then stay registered as writable.
+    /// The body of an event stream may have nothing to send yet, this then returns
+    /// with `waiting` set until more events are sent.
+    fn write_to<W: Write>(
+        &mut self,
+        stream: &mut W,
+        bytes_written: &mut usize,
+    ) -> io::Result<bool> {
+        let header_data_len = self.header_data.len();
+        let start = *bytes_written;
+        self.waiting = false;
+        loop {
+            let (data, in_chunk) = if *bytes_written < header_data_len {
+                (&self.header_data[*bytes_written..], false)
+            } else if self.head {
+                return flushed(stream);
+            } else if !self.inner.body().is_stream() {
+                let body = self.inner.body().bytes();
+                let body_written = *bytes_written - header_data_len;
+                if body_written < body.len() {
+                    (&body[body_written..], false)
+                } else {
+                    return flushed(stream);
+                }
+            } else if self.chunk_written < self.chunk.len() {
+                (&self.chunk[self.chunk_written..], true)
+            } else if self.stream_done {
+                return flushed(stream);
+            } else if *bytes_written - start >= STREAM_WRITE_BUDGET {
+                return Ok(false);
+            } else if self.inner.body().is_waiting() {
+                self.waiting = true;
+                return flushed(stream).map(|_| false);
+            } else {
+                self.next_chunk()?;
+                continue;
+            };
+            match stream.write(data) {
+                Ok(n) => {
+                    *bytes_written += n;
+                    if in_chunk {
+                        self.chunk_written += n;
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Pull the next chunk of a streamed body, framing it when sending chunked
+    fn next_chunk(&mut self) -> io::Result<()> {
+        self.chunk.clear();
+        self.chunk_written = 0;
+        let chunked = self.chunked;
+        match self.inner.body_mut().next_chunk()? {
+            // an empty chunk would read as the end of the body
+            Some(ref data) if data.is_empty() => {}
+            Some(data) => {
+                if chunked {
+                    self.chunk
+                        .extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+                    self.chunk.extend_from_slice(&data);
+                    self.chunk.extend_from_slice(b"\r\n");
+                } else {
+                    self.chunk = data;
+                }
+            }
+            None => {
+                if chunked {
+                    self.chunk.extend_from_slice(b"0\r\n\r\n");
+                }
+                self.stream_done = true;
+            }
+        }
+        Ok(())
+    }
 }
 impl std::ops::Deref for ResponseWrapper {
-    type Target = http::Response<Vec<u8>>;
+    type Target = http::Response<Body>;
     fn deref(&self) -> &Self::Target {
         &self.inner
     }
@@ -138,8 +459,20 @@ impl std::ops::DerefMut for ResponseWrapper {
     }
 }
 
+/// Flush `stream` once a response is written. Returns `false` while a transport
+/// still holds some of it back, until the socket is writable again
+fn flushed<W: Write>(stream: &mut W) -> io::Result<bool> {
+    match stream.flush() {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        // If flushing fails, something bad probably happened.
+        // If it didn't fail because of a connection error (connection
+        // is still alive), it will eventually be flushed by the os
+        _ => Ok(true),
+    }
+}
+
 /// Represent everything about a request except its (possible) body
-type RequestHead = http::Request<()>;
+pub type RequestHead = http::Request<()>;
 
 /// `Request` received and used by handlers. Wraps & `deref`s to an `http::Request`
 /// and patches `Request::body` to return the correct slice of bytes from the
@@ -149,9 +482,51 @@ pub struct Request {
     body_start: usize,
 }
 impl Request {
+    /// The request body. A `Transfer-Encoding: chunked` body is already de-chunked
     pub fn body(&self) -> &[u8] {
         &self.inner.body()[self.body_start..]
     }
+
+    /// Trailer fields sent after a chunked request body, if any
+    pub fn trailers(&self) -> Option<&header::HeaderMap> {
+        self.inner
+            .extensions()
+            .get::<http_stream::Trailers>()
+            .map(|trailers| &trailers.0)
+    }
+
+    /// Which of the server's listeners the request came in on: `0` for the address
+    /// the server was initialized with, then one per call to
+    /// [`Server::listen`](struct.Server.html#method.listen) or
+    /// [`Server::listen_unix`](struct.Server.html#method.listen_unix), in order
+    pub fn listener(&self) -> usize {
+        self.inner
+            .extensions()
+            .get::<ListenerIndex>()
+            .map(|index| index.0)
+            .unwrap_or(0)
+    }
+
+    /// Credentials of the process that sent the request, for requests received on
+    /// a Unix socket. See [`Server::unix`](struct.Server.html#method.unix)
+    #[cfg(unix)]
+    pub fn peer_credentials(&self) -> Option<&PeerCredentials> {
+        self.inner.extensions().get::<PeerCredentials>()
+    }
+
+    /// What was negotiated during the TLS handshake, `None` for plain HTTP requests
+    #[cfg(feature = "tls")]
+    pub fn tls(&self) -> Option<&TlsInfo> {
+        self.inner.extensions().get::<TlsInfo>()
+    }
+
+    /// Id of the last event received by a client reconnecting to an event stream,
+    /// from its `Last-Event-ID` header. See [`EventStream`](struct.EventStream.html)
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.headers()
+            .get("last-event-id")
+            .and_then(|id| id.to_str().ok())
+    }
 }
 impl std::ops::Deref for Request {
     type Target = http::Request<Vec<u8>>;
@@ -165,73 +540,91 @@ impl std::ops::DerefMut for Request {
     }
 }
 
-/// Represent the tcp socket & streams being polled by `mio`
+/// Represent the listening sockets & streams being polled by `mio`
 enum Socket {
     Listener {
-        listener: mio::net::TcpListener,
-    },
-    Stream {
-        stream: mio::net::TcpStream,
-        reader: HttpStreamReader,
-        request: Option<RequestHead>,
-        done_reading: bool,
-        bytes_written: usize,
+        listener: Listener,
+        index: ListenerIndex,
     },
+    Stream(Box<Connection>),
 }
 impl Socket {
-    fn new_listener(l: mio::net::TcpListener) -> Self {
-        Socket::Listener { listener: l }
-    }
-
-    /// Construct a new `Stream` variant accepts from a tcp listener
-    fn new_stream(s: mio::net::TcpStream, reader: HttpStreamReader) -> Self {
-        Socket::Stream {
-            stream: s,
-            reader,
-            request: None,
-            done_reading: false,
-            bytes_written: 0,
-        }
-    }
-
-    /// Construct a "continued" stream. Stream reading hasn't been completed yet
-    fn continued_stream(
-        stream: mio::net::TcpStream,
-        reader: HttpStreamReader,
-        request: Option<RequestHead>,
-        done_reading: bool,
-        bytes_written: usize,
-    ) -> Self {
-        Socket::Stream {
-            stream,
-            reader,
-            request,
-            done_reading,
-            bytes_written,
-        }
+    fn new_listener(l: Listener, index: ListenerIndex) -> Self {
+        Socket::Listener { listener: l, index }
     }
 }
 
+#[derive(Clone)]
 pub struct Server {
-    addr: Option<String>,
+    addrs: Vec<Address>,
     no_delay: bool,
+    worker_threads: usize,
+    reactors: usize,
+    reuse_port: bool,
+    pin_reactors: bool,
+    signal_grace: Option<Duration>,
+    timeouts: Timeouts,
+    max_connections: Option<usize>,
+    reject_excess_connections: bool,
+    limits: Limits,
+    error_handler: Option<ErrorHandler>,
+    head_as_get: bool,
+    expect_handler: Option<ExpectHandler>,
+    http2: bool,
+    websocket_handler: Option<WebSocketHandler>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+    #[cfg(unix)]
+    unix_permissions: Option<u32>,
+    #[cfg(unix)]
+    unix_unlink: bool,
 }
 impl Server {
     /// Initialize a new default `Server` to run on `addr`
     #[cfg(not(target_os = "wasi"))]
     pub fn new(addr: &str) -> Result<Self> {
-        Ok(Self {
-            addr: Some(addr.to_string()),
-            no_delay: false,
-        })
+        Ok(Self::with_addr(Address::Tcp(addr.to_string())))
+    }
+
+    /// Initialize a new default `Server` to run on the Unix socket at `path`.
+    /// Requests tell the credentials of the process that connected, see
+    /// [`Request::peer_credentials`](struct.Request.html#method.peer_credentials).
+    /// Connections to a Unix socket are always served without TLS
+    #[cfg(unix)]
+    pub fn unix<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        Ok(Self::with_addr(Address::Unix(path.as_ref().to_path_buf())))
     }
 
     /// Initialize a new default `Server` to run on preopened socket
     pub fn preopened() -> Result<Self> {
-        Ok(Self {
-            addr: None,
+        Ok(Self::with_addr(Address::Preopened))
+    }
+
+    fn with_addr(addr: Address) -> Self {
+        Self {
+            addrs: vec![addr],
             no_delay: false,
-        })
+            worker_threads: 0,
+            reactors: 1,
+            reuse_port: true,
+            pin_reactors: false,
+            signal_grace: None,
+            timeouts: Timeouts::default(),
+            max_connections: None,
+            reject_excess_connections: false,
+            limits: Limits::default(),
+            error_handler: None,
+            head_as_get: false,
+            expect_handler: None,
+            http2: false,
+            websocket_handler: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(unix)]
+            unix_permissions: None,
+            #[cfg(unix)]
+            unix_unlink: true,
+        }
     }
 
     /// Configure `tcp_nodelay` setting for each server socket.
@@ -254,247 +647,874 @@ impl Server {
         self
     }
 
-    /// Start the server using the given handler function
-    pub fn start<F>(&self, func: F) -> Result<()>
+    /// Also listen on `addr`, all listeners are served by the same event loop(s) and
+    /// handler. Can be called several times, e.g. for both an IPv4 and an IPv6
+    /// address, or an admin port next to the public one. The handler tells them
+    /// apart with [`Request::listener`](struct.Request.html#method.listener).
+    /// Default: only the address the server was initialized with
+    #[cfg(not(target_os = "wasi"))]
+    pub fn listen(&mut self, addr: &str) -> &mut Self {
+        self.addrs.push(Address::Tcp(addr.to_string()));
+        self
+    }
+
+    /// Also listen on the Unix socket at `path`, see [`listen`](#method.listen) and
+    /// [`unix`](#method.unix).
+    /// Default: only the address the server was initialized with
+    #[cfg(unix)]
+    pub fn listen_unix<P: AsRef<std::path::Path>>(&mut self, path: P) -> &mut Self {
+        self.addrs.push(Address::Unix(path.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Configure the permissions of the socket file of a server running on a
+    /// Unix socket, e.g. `0o660` to only let the owner and its group connect.
+    /// The umask is tightened while binding, so the socket is created with them.
+    /// Default: left to the process umask
+    #[cfg(unix)]
+    pub fn unix_permissions(&mut self, mode: u32) -> &mut Self {
+        self.unix_permissions = Some(mode);
+        self
+    }
+
+    /// Configure whether a socket file already at the path of a server running on
+    /// a Unix socket, e.g. left by a previous run, is removed before binding. Other
+    /// kinds of files are never removed, binding fails instead.
+    /// Default: `true`
+    #[cfg(unix)]
+    pub fn unix_unlink(&mut self, unlink: bool) -> &mut Self {
+        self.unix_unlink = unlink;
+        self
+    }
+
+    /// Configure the number of worker threads running the handler when the server
+    /// is started with [`start_threaded`](#method.start_threaded).
+    /// Default: `0`, one thread per available cpu
+    #[cfg(not(target_os = "wasi"))]
+    pub fn worker_threads(&mut self, threads: usize) -> &mut Self {
+        self.worker_threads = threads;
+        self
+    }
+
+    /// Configure the number of event loops (reactors) when the server is started
+    /// with [`start_threaded`](#method.start_threaded). Each reactor polls its own
+    /// listener and connections on a dedicated thread, all of them share the
+    /// worker threads running the handler.
+    /// Default: `1`
+    #[cfg(not(target_os = "wasi"))]
+    pub fn reactors(&mut self, reactors: usize) -> &mut Self {
+        self.reactors = reactors;
+        self
+    }
+
+    /// Configure whether each reactor binds its own listener with `SO_REUSEPORT`,
+    /// letting the kernel spread new connections evenly between them. When disabled,
+    /// or where `SO_REUSEPORT` isn't available, the reactors share a single listener.
+    /// Default: `true`
+    #[cfg(not(target_os = "wasi"))]
+    pub fn reuse_port(&mut self, reuse_port: bool) -> &mut Self {
+        self.reuse_port = reuse_port;
+        self
+    }
+
+    /// Configure whether reactor threads are pinned to cpus, the n-th reactor
+    /// running on the n-th cpu. Only supported on Linux.
+    /// Default: `false`
+    #[cfg(not(target_os = "wasi"))]
+    pub fn pin_reactors(&mut self, pin: bool) -> &mut Self {
+        self.pin_reactors = pin;
+        self
+    }
+
+    /// Configure how long a client may take to send the header block of a request,
+    /// from its first byte on. Requests taking longer are answered with a
+    /// `408 Request Timeout` and the connection is closed. `None` disables it.
+    /// Default: `30s`
+    pub fn header_read_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.timeouts.header = timeout;
+        self
+    }
+
+    /// Configure how long a client may take to send the body of a request, once
+    /// its headers were received. Requests taking longer are answered with a
+    /// `408 Request Timeout` and the connection is closed, HTTP/2 connections with
+    /// a stream still receiving its body are closed. Raise it for large uploads over
+    /// slow links. `None` disables it.
+    /// Default: `30s`
+    pub fn body_read_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.timeouts.body = timeout;
+        self
+    }
+
+    /// Configure how long a connection may stay open without a request in progress,
+    /// both after it's accepted and between keep-alive requests. `None` disables it.
+    /// Default: `60s`
+    pub fn idle_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.timeouts.idle = timeout;
+        self
+    }
+
+    /// Configure how long writing responses may go without making progress, for
+    /// clients that stop reading. The connection is closed once it expires.
+    /// `None` disables it.
+    /// Default: `60s`
+    pub fn write_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.timeouts.write = timeout;
+        self
+    }
+
+    /// Configure how long an event stream response may go without sending anything
+    /// before a comment is written to it, so clients and proxies don't take the
+    /// connection for dead. `None` disables it.
+    /// Default: `15s`
+    pub fn event_stream_keep_alive(&mut self, interval: Option<Duration>) -> &mut Self {
+        self.timeouts.events = interval;
+        self
+    }
+
+    /// Configure the size limits enforced on incoming requests.
+    /// Default: `Limits::default()`, see [`Limits`](struct.Limits.html)
+    pub fn limits(&mut self, limits: Limits) -> &mut Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Render the responses the server sends on its own when a request fails, e.g.
+    /// a malformed request or one over the [`limits`](#method.limits). The connection
+    /// is closed after the response. The status to use is given by
+    /// [`ErrorKind::status`](enum.ErrorKind.html#method.status).
+    /// Default: the status with its reason phrase as body
+    pub fn error_handler<F, B>(&mut self, func: F) -> &mut Self
     where
-        F: 'static + Fn(Request) -> Response<Vec<u8>>,
+        F: 'static + Fn(&Error) -> Response<B> + Send + Sync,
+        B: Into<Body>,
     {
-        let mut sockets = slab::Slab::with_capacity(1024);
-        let mut server = get_tcp_listener(self.addr.clone());
+        self.error_handler = Some(std::sync::Arc::new(move |e: &Error| {
+            func(e).map(Into::into)
+        }));
+        self
+    }
+
+    /// Configure whether `HEAD` requests are handed to the handler as `GET` requests,
+    /// so a handler only needs to answer `GET`. The body of the response to a `HEAD`
+    /// request is never sent, its headers are the same as for a `GET`.
+    /// Default: `false`
+    pub fn head_as_get(&mut self, head_as_get: bool) -> &mut Self {
+        self.head_as_get = head_as_get;
+        self
+    }
+
+    /// Decide whether clients sending `Expect: 100-continue` may go on with the body
+    /// of their request, based on its head alone. Returning `None` answers with a
+    /// `100 Continue`, returning a response (e.g. a `413` or `401`) sends it instead
+    /// and closes the connection without reading the body.
+    /// Default: every body is accepted
+    pub fn expect_continue<F, B>(&mut self, func: F) -> &mut Self
+    where
+        F: 'static + Fn(&RequestHead) -> Option<Response<B>> + Send + Sync,
+        B: Into<Body>,
+    {
+        self.expect_handler = Some(std::sync::Arc::new(move |head: &RequestHead| {
+            func(head).map(|resp| resp.map(Into::into))
+        }));
+        self
+    }
+
+    /// Let clients speak HTTP/2 over plain connections, either from the start
+    /// (prior knowledge) or after upgrading an HTTP/1.1 request with `Upgrade: h2c`.
+    /// With [`tls`](#method.tls), `h2` is offered through ALPN as well.
+    /// Requests of all streams are handed to the same handler, run them on worker
+    /// threads with [`start_threaded`](#method.start_threaded) to answer them
+    /// concurrently.
+    /// Default: `false`
+    pub fn http2(&mut self, http2: bool) -> &mut Self {
+        self.http2 = http2;
+        self
+    }
+
+    /// Accept WebSocket connections. `func` gets the head of each `Upgrade: websocket`
+    /// request and returns the callback receiving the messages of that connection,
+    /// or `None` to hand the request to the handler as usual. Invalid handshakes are
+    /// answered with an error response without calling `func`. Callbacks run on the
+    /// event loop and reply through the `WebSocket` they're given, pings and the close
+    /// handshake are taken care of. Other threads send through a `WebSocket::sender`,
+    /// taken e.g. on `Message::Open`. Messages are limited to `Limits::max_body_bytes`.
+    /// Default: WebSocket requests go to the handler
+    pub fn websocket<F, C>(&mut self, func: F) -> &mut Self
+    where
+        F: 'static + Fn(&RequestHead) -> Option<C> + Send + Sync,
+        C: 'static + FnMut(&mut WebSocket, Message),
+    {
+        self.websocket_handler = Some(std::sync::Arc::new(move |head: &RequestHead| {
+            func(head).map(|callback| Box::new(callback) as websocket::Callback)
+        }));
+        self
+    }
+
+    /// Serve HTTPS, using the certificate chain and private key in the PEM files at
+    /// `cert_path` and `key_path`. Handshakes happen on the event loop, the handler
+    /// gets the decrypted requests as usual, see `Request::tls` for what was negotiated.
+    /// `http/1.1` is offered through ALPN, and `h2` with [`http2`](#method.http2).
+    /// Default: plain HTTP
+    #[cfg(feature = "tls")]
+    pub fn tls(&mut self, cert_path: &str, key_path: &str) -> Result<&mut Self> {
+        self.tls
+            .get_or_insert_with(TlsConfig::default)
+            .set_default(cert_path, key_path)?;
+        Ok(self)
+    }
+
+    /// Serve HTTPS, presenting the certificate chain and private key in the PEM
+    /// files at `cert_path` and `key_path` to clients asking for `server_name`
+    /// through SNI. Can be called once per host name, clients asking for none of
+    /// them get the certificate set with [`tls`](#method.tls), or fail the
+    /// handshake if there's none.
+    /// Default: no per host name certificates
+    #[cfg(feature = "tls")]
+    pub fn tls_sni(
+        &mut self,
+        server_name: &str,
+        cert_path: &str,
+        key_path: &str,
+    ) -> Result<&mut Self> {
+        self.tls
+            .get_or_insert_with(TlsConfig::default)
+            .add_server_name(server_name, cert_path, key_path)?;
+        Ok(self)
+    }
+
+    /// Ask clients for a certificate and verify it against the CA certificates in
+    /// the PEM file at `ca_path`. Clients without one are turned down during the
+    /// handshake if `required`, otherwise their requests get through without peer
+    /// certificates. See `TlsInfo::peer_certificates` and `TlsInfo::peer_subject`.
+    /// Default: no client certificates
+    #[cfg(feature = "tls")]
+    pub fn tls_client_auth(&mut self, ca_path: &str, required: bool) -> Result<&mut Self> {
+        self.tls
+            .get_or_insert_with(TlsConfig::default)
+            .set_client_auth(ca_path, required)?;
+        Ok(self)
+    }
+
+    /// Configure the maximum number of open connections per reactor. Once it's
+    /// reached the server stops accepting, leaving new connections in the listen
+    /// backlog, until some of the open ones close. `None` means no limit.
+    /// Default: `None`
+    pub fn max_connections(&mut self, max: Option<usize>) -> &mut Self {
+        self.max_connections = max;
+        self
+    }
+
+    /// Configure whether connections over the [`max_connections`](#method.max_connections)
+    /// limit are accepted and answered with a `503 Service Unavailable` right away,
+    /// instead of waiting in the listen backlog.
+    /// Default: `false`
+    pub fn reject_excess_connections(&mut self, reject: bool) -> &mut Self {
+        self.reject_excess_connections = reject;
+        self
+    }
+
+    /// Shut the server down gracefully on `SIGTERM`, giving in-flight requests
+    /// up to `grace` to finish like [`ServerHandle::shutdown`](struct.ServerHandle.html#method.shutdown),
+    /// and stop it immediately on `SIGINT`. The signal handlers are process wide,
+    /// so only one server should enable this.
+    /// Default: signals aren't handled
+    #[cfg(unix)]
+    pub fn shutdown_on_signals(&mut self, grace: Duration) -> &mut Self {
+        self.signal_grace = Some(grace);
+        self
+    }
+
+    /// Start the server using the given handler function.
+    ///
+    /// Handlers may return any `Response<B>` where `B: Into<Body>`, e.g. a `Vec<u8>`
+    /// or a streamed `Body`. The handler runs synchronously on the event loop, so a
+    /// slow handler holds up every other connection, see
+    /// [`start_threaded`](#method.start_threaded)
+    pub fn start<F, B>(&self, func: F) -> Result<()>
+    where
+        F: 'static + Fn(Request) -> Response<B>,
+        B: Into<Body>,
+    {
+        let poll = mio::Poll::new()?;
+        let shutdown = Shutdown::new();
+        #[cfg(not(target_os = "wasi"))]
+        let wakeups = {
+            let waker = Arc::new(mio::Waker::new(poll.registry(), WAKER_TOKEN)?);
+            shutdown.add_waker(waker.clone());
+            Wakeups::new(waker)
+        };
+        #[cfg(target_os = "wasi")]
+        let wakeups = Wakeups::new();
+        let listeners = self.listeners()?;
+        self.run(
+            poll,
+            listeners,
+            &Inline(move |request| func(request).map(Into::into)),
+            &shutdown,
+            wakeups,
+            true,
+        )
+    }
+
+    /// Start the server in the background using the given handler function, the
+    /// handler runs synchronously on the server's event loop thread. The returned
+    /// [`ServerHandle`](struct.ServerHandle.html) shuts the server down
+    #[cfg(not(target_os = "wasi"))]
+    pub fn spawn<F, B>(&self, func: F) -> Result<ServerHandle>
+    where
+        F: 'static + Fn(Request) -> Response<B> + Send,
+        B: Into<Body>,
+    {
+        let poll = mio::Poll::new()?;
+        let shutdown = Shutdown::new();
+        let waker = Arc::new(mio::Waker::new(poll.registry(), WAKER_TOKEN)?);
+        shutdown.add_waker(waker.clone());
+        let wakeups = Wakeups::new(waker);
+        let listeners = self.listeners()?;
+        let local_addrs = listeners
+            .iter()
+            .map(Listener::local_addr)
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let server = self.clone();
+        let thread = {
+            let shutdown = shutdown.clone();
+            thread::Builder::new()
+                .name("mini_http-server".to_string())
+                .spawn(move || {
+                    let dispatch = Inline(move |request| func(request).map(Into::into));
+                    server.run(poll, listeners, &dispatch, &shutdown, wakeups, true)
+                })?
+        };
+        Ok(ServerHandle {
+            shutdown,
+            thread: Some(thread),
+            local_addrs,
+        })
+    }
+
+    /// Start the server, running the given handler function on a pool of
+    /// [`worker_threads`](#method.worker_threads) instead of the event loop.
+    /// Responses are still written back in the order the requests were received.
+    ///
+    /// Connections are spread over the configured number of [`reactors`](#method.reactors)
+    #[cfg(not(target_os = "wasi"))]
+    pub fn start_threaded<F, B>(&self, func: F) -> Result<()>
+    where
+        F: 'static + Fn(Request) -> Response<B> + Send + Sync,
+        B: Into<Body>,
+    {
+        let reactors = std::cmp::max(self.reactors, 1);
+        let shutdown = Shutdown::new();
+        let mut polls = Vec::with_capacity(reactors);
+        let mut wakers = Vec::with_capacity(reactors);
+        for _ in 0..reactors {
+            let poll = mio::Poll::new()?;
+            let waker = Arc::new(mio::Waker::new(poll.registry(), WAKER_TOKEN)?);
+            shutdown.add_waker(waker.clone());
+            wakers.push(waker);
+            polls.push(poll);
+        }
+        let listeners = self.reactor_listeners(reactors)?;
+
+        let threads = match self.worker_threads {
+            0 => thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+            n => n,
+        };
+        info!("** Running handlers on {} worker threads **", threads);
+        let pool = WorkerPool::new(
+            threads,
+            move |request| func(request).map(Into::into),
+            wakers.clone(),
+        )?;
+
+        if reactors == 1 {
+            let poll = polls.pop().unwrap();
+            let listeners = listeners.into_iter().next().unwrap();
+            let wakeups = Wakeups::new(wakers.pop().unwrap());
+            return self.run(
+                poll,
+                listeners,
+                &pool.dispatcher(0),
+                &shutdown,
+                wakeups,
+                true,
+            );
+        }
+        info!("** Running {} reactors **", reactors);
+        thread::scope(|scope| {
+            let handles = polls
+                .into_iter()
+                .zip(listeners)
+                .zip(wakers)
+                .enumerate()
+                .map(|(n, ((poll, listeners), waker))| {
+                    let pool = &pool;
+                    let shutdown = &shutdown;
+                    thread::Builder::new()
+                        .name(format!("mini_http-reactor-{}", n))
+                        .spawn_scoped(scope, move || {
+                            if self.pin_reactors {
+                                pin_to_cpu(n);
+                            }
+                            // the first reactor handles signals, shutting down all of them
+                            let wakeups = Wakeups::new(waker);
+                            let res = self.run(
+                                poll,
+                                listeners,
+                                &pool.dispatcher(n),
+                                shutdown,
+                                wakeups,
+                                n == 0,
+                            );
+                            if let Err(ref e) = res {
+                                error!("Reactor {} stopped: {}", n, e);
+                            }
+                            res
+                        })
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap_or_else(|_| bail!("Reactor panicked")))
+                .collect::<Result<Vec<_>>>()
+                .map(|_| ())
+        })
+    }
+
+    /// Bind the listeners the server runs on, in the order of its addresses
+    fn listeners(&self) -> Result<Vec<Listener>> {
+        self.addrs.iter().map(|addr| self.listener(addr)).collect()
+    }
+
+    /// Bind a listener to `addr`
+    fn listener(&self, addr: &Address) -> Result<Listener> {
+        match *addr {
+            #[cfg(unix)]
+            Address::Unix(ref path) => Ok(Listener::Unix(unix::bind(
+                path,
+                self.unix_unlink,
+                self.unix_permissions,
+            )?)),
+            Address::Tcp(ref addr) => Ok(Listener::Tcp(get_tcp_listener(Some(addr.clone()))?)),
+            Address::Preopened => Ok(Listener::Tcp(get_tcp_listener(None)?)),
+        }
+    }
+
+    /// Create the listeners of each reactor, one per address of the server
+    #[cfg(not(target_os = "wasi"))]
+    fn reactor_listeners(&self, reactors: usize) -> Result<Vec<Vec<Listener>>> {
+        let mut listeners = (0..reactors).map(|_| Vec::new()).collect::<Vec<_>>();
+        for addr in &self.addrs {
+            for (reactor, listener) in self
+                .address_listeners(addr, reactors)?
+                .into_iter()
+                .enumerate()
+            {
+                listeners[reactor].push(listener);
+            }
+        }
+        Ok(listeners)
+    }
 
-        // initialize poll
-        let mut poll = mio::Poll::new()?;
+    /// Create one listener to `addr` per reactor. Either all bound with
+    /// `SO_REUSEPORT`, or handles to the same listener
+    #[cfg(not(target_os = "wasi"))]
+    fn address_listeners(&self, addr: &Address, reactors: usize) -> Result<Vec<Listener>> {
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
         {
-            // register our tcp listener
-            let entry = sockets.vacant_entry();
-            let server_token = Token(entry.key());
-            poll.registry()
-                .register(&mut server, server_token, Interest::READABLE)?;
-            entry.insert(Socket::new_listener(server));
+            if let (true, true, Address::Tcp(addr)) = (reactors > 1, self.reuse_port, addr) {
+                return (0..reactors)
+                    .map(|_| get_reuseport_listener(addr).map(Listener::Tcp))
+                    .collect();
+            }
+        }
+        let listener = self.listener(addr)?;
+        let mut listeners = Vec::with_capacity(reactors);
+        for _ in 1..reactors {
+            #[cfg(unix)]
+            {
+                // a duplicated descriptor of the same socket, each reactor
+                // registers it with its own `mio::Poll`
+                listeners.push(listener.try_clone()?);
+            }
+            #[cfg(windows)]
+            {
+                bail!("Sharing a listener between reactors isn't supported on windows");
+            }
         }
+        listeners.insert(0, listener);
+        Ok(listeners)
+    }
 
-        if let Some(addr) = &self.addr {
+    /// Whether `connections` open connections are at the `max_connections` limit
+    fn at_connection_limit(&self, connections: usize) -> bool {
+        self.max_connections
+            .map(|max| connections >= max)
+            .unwrap_or(false)
+    }
+
+    /// Run the event loop, handing parsed requests to `dispatch`, until `shutdown`
+    /// is triggered and the open connections are done. `wakeups` collects the
+    /// connections with new events to write. With `handle_signals` set,
+    /// `shutdown` is also triggered by signals if configured
+    fn run<D: Dispatch>(
+        &self,
+        mut poll: mio::Poll,
+        listeners: Vec<Listener>,
+        dispatch: &D,
+        shutdown: &Shutdown,
+        wakeups: Arc<Wakeups>,
+        handle_signals: bool,
+    ) -> Result<()> {
+        let mut sockets = slab::Slab::with_capacity(1024);
+
+        #[cfg(unix)]
+        let mut signals = match self.signal_grace {
+            Some(_) if handle_signals => Some(Signals::install(poll.registry(), SIGNAL_TOKEN)?),
+            _ => None,
+        };
+        #[cfg(not(unix))]
+        let _ = handle_signals;
+
+        // register our listeners
+        for (index, (mut listener, addr)) in listeners.into_iter().zip(&self.addrs).enumerate() {
+            let entry = sockets.vacant_entry();
+            let token = Token(entry.key());
+            poll.registry()
+                .register(&mut listener, token, Interest::READABLE)?;
+            entry.insert(Socket::new_listener(listener, ListenerIndex(index)));
             info!("** Listening on {} **", addr);
-        } else {
-            info!("** Using preopened socket FD 3 **");
         }
+        // number of listeners in `sockets`, the other sockets are connections
+        let mut listening = self.addrs.len();
 
+        #[cfg(feature = "tls")]
+        let tls = self.tls.as_ref().map(|tls| tls.server_config(self.http2));
+
+        let mut next_conn_id = 0;
+        let mut draining = false;
+        // the listeners, while accepting is paused at the connection limit
+        let mut paused: Vec<(Listener, ListenerIndex)> = Vec::new();
+        let mut timers = Timers::new(self.timeouts);
         let mut events = mio::Events::with_capacity(1024);
         loop {
             debug!("Beginning of loop");
-            poll.poll(&mut events, None)?;
-            'next_event: for e in &events {
+            // wake up for the earliest connection timeout, or the shutdown deadline
+            let deadline = match (timers.next_deadline(), shutdown.deadline()) {
+                (Some(timer), Some(deadline)) if draining => Some(timer.min(deadline)),
+                (None, Some(deadline)) if draining => Some(deadline),
+                (timer, _) => timer,
+            };
+            if let Err(e) = poll.poll(&mut events, deadline.map(shutdown::until)) {
+                // signals interrupt the wait, they're picked up through their self-pipe
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e.into());
+                }
+            }
+            for e in &events {
                 let token = e.token();
-                match sockets.remove(token.into()) {
-                    Socket::Listener { mut listener } => {
-                        if e.is_readable() {
-                            match listener.accept() {
-                                Ok((mut sock, addr)) => {
-                                    debug!("opened socket to: {:?}", addr);
-
-                                    // register the newly opened socket
-                                    let entry = sockets.vacant_entry();
-                                    let token = Token(entry.key());
-                                    poll.registry().register(
-                                        &mut sock,
-                                        token,
-                                        Interest::READABLE | Interest::WRITABLE,
-                                    )?;
-                                    entry.insert(Socket::new_stream(sock, HttpStreamReader::new()));
+
+                #[cfg(not(target_os = "wasi"))]
+                {
+                    if token == WAKER_TOKEN {
+                        // handlers finished on worker threads, write their responses
+                        for (ticket, response) in dispatch.completed() {
+                            let close = match sockets.get_mut(ticket.token.into()) {
+                                Some(&mut Socket::Stream(ref mut conn))
+                                    if conn.id == ticket.conn_id =>
+                                {
+                                    conn.complete(ticket.seq, response);
+                                    conn.serve(ticket.token, dispatch)
                                 }
-                                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
-                                Err(e) => {
-                                    error!("{:?} - Encountered error while accepting the connection: {:?}", token, e);
+                                _ => {
+                                    debug!(
+                                        "{:?} - Connection closed before its response was ready",
+                                        ticket.token
+                                    );
+                                    continue;
                                 }
                             };
+                            update_stream(
+                                poll.registry(),
+                                &mut sockets,
+                                &mut timers,
+                                ticket.token,
+                                close,
+                            )?;
                         }
-                        // reregister listener
-                        let entry = sockets.vacant_entry();
-                        let token = Token(entry.key());
-                        poll.registry()
-                            .reregister(&mut listener, token, Interest::READABLE)?;
-                        entry.insert(Socket::new_listener(listener));
+                        continue;
                     }
-                    Socket::Stream {
-                        mut stream,
-                        mut reader,
-                        request,
-                        mut done_reading,
-                        mut bytes_written,
-                    } => {
-                        if e.is_read_closed() || e.is_write_closed() {
-                            poll.registry().deregister(&mut stream)?;
-                            continue 'next_event;
-                        }
+                }
 
-                        // Try reading and parsing a request from this stream.
-                        // `try_build_request` will return `None` until the request is parsed and the
-                        // body is done being read. After that, `done_reading` will be set
-                        // to `true`. At that point, if this socket is readable, we still need to
-                        // check if it's been closed, but we will no longer try parsing the request
-                        // bytes
-                        let (mut request, err_response): (
-                            Option<RequestHead>,
-                            Option<ResponseWrapper>,
-                        ) = if e.is_readable() {
-                            let mut buf = [0; 256];
-                            let stream_close = loop {
-                                match stream.read(&mut buf) {
-                                    Ok(0) => {
-                                        // the stream has ended for real
-                                        break true;
-                                    }
-                                    Ok(n) => {
-                                        reader.receive_chunk(&buf[..n]);
-                                        debug!("{:?} - Read {} bytes", token, n);
-                                    }
-                                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                                        break false
-                                    }
-                                    Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => {
-                                        break true
-                                    }
-                                    Err(e) => {
-                                        error!("{:?} - Encountered error while reading from socket: {:?}", token, e);
-                                        // let this socket die, jump to the next event
-                                        break true;
-                                    }
-                                }
-                            };
-                            if stream_close {
-                                debug!("{:?} - Stream closed. Killing socket.", token);
-                                // jump to the next mio event
-                                // TODO: if we have a `receiver` (a handler is running)
-                                //       try shutting it down
-                                poll.registry().deregister(&mut stream)?;
-                                continue 'next_event;
-                            }
-                            if done_reading {
-                                (request, None)
+                #[cfg(unix)]
+                {
+                    if token == SIGNAL_TOKEN {
+                        let grace = self.signal_grace.unwrap_or_default();
+                        for signal in signals.as_mut().map(|s| s.received()).unwrap_or_default() {
+                            if signal == libc::SIGTERM {
+                                info!("** Received SIGTERM **");
+                                shutdown.trigger(Instant::now() + grace);
                             } else {
-                                match reader.try_build_request() {
-                                    Ok(r) => (r, None),
-                                    Err(e) => {
-                                        // TODO: return the proper status-code per error
-                                        error!(
-                                            "{:?} - Encountered error while parsing: {}",
-                                            token, e
-                                        );
-                                        (
-                                            None,
-                                            Some(ResponseWrapper::new(
-                                                Response::builder()
-                                                    .status(400)
-                                                    .body(b"bad request".to_vec())
-                                                    .unwrap(),
-                                            )),
-                                        )
-                                    }
-                                }
+                                info!("** Received SIGINT **");
+                                shutdown.trigger(Instant::now());
                             }
-                        } else {
-                            (request, None)
-                        };
-                        if request.is_some() || err_response.is_some() {
-                            done_reading = true;
-                            debug!("Reading is done for token {:?}", token);
                         }
+                        continue;
+                    }
+                }
 
-                        // Once the request is parsed, this block will execute once.
-                        // The head-only request (RequestHead) will be converted into
-                        // a public `Request` and the `HttpStreamReader`s `read_buf` will be
-                        // swapped into the new `Request`s body. The provided
-                        // `func` handler will be started for later retrieval
-                        let response = if let Some(req) = request.take() {
-                            debug!("Begin processing the response for token {:?}", token);
-                            let (parts, _) = req.into_parts();
-                            let mut body = vec![];
-                            std::mem::swap(&mut body, &mut reader.read_buf);
-                            let request = Request {
-                                inner: http::Request::from_parts(parts, body),
-                                body_start: reader.headers_length,
-                            };
-
-                            let resp = func(request);
-                            let mut resp = ResponseWrapper::new(resp);
-                            resp.serialize_headers();
-                            debug!("Headers serialized for token {:?}", token);
-                            Some(resp)
+                let close = match sockets.get_mut(token.into()) {
+                    // already closed by an earlier event of this batch
+                    None => continue,
+                    Some(&mut Socket::Listener { .. }) => None,
+                    Some(&mut Socket::Stream(ref mut conn)) => {
+                        if e.is_read_closed() || e.is_write_closed() {
+                            Some(true)
+                        } else if e.is_readable() && conn.read(token) {
+                            debug!("{:?} - Stream closed. Killing socket.", token);
+                            // TODO: if a handler is running on a worker thread
+                            //       try shutting it down
+                            Some(true)
                         } else {
-                            None
-                        };
+                            Some(conn.serve(token, dispatch))
+                        }
+                    }
+                };
+                if let Some(close) = close {
+                    update_stream(poll.registry(), &mut sockets, &mut timers, token, close)?;
+                    continue;
+                }
 
-                        // See if a `ResponseWrapper` is available
-
-                        // If we have a `ResponseWrapper`, start writing its headers and body
-                        // back to the stream
-                        let mut done_write = false;
-                        if let Some(ref resp) = response {
-                            debug!("Response body ready to be written for token {:?}", token);
-                            if e.is_writable() {
-                                debug!("Body writeable for token {:?}", token);
-                                let header_data_len = resp.header_data.len();
-                                let body_len = resp.body().len();
-                                let total_len = header_data_len + body_len;
-                                'write: loop {
-                                    let (data, read_start) = if bytes_written < header_data_len {
-                                        (&resp.header_data, bytes_written)
-                                    } else if bytes_written < total_len {
-                                        (resp.body(), bytes_written - header_data_len)
-                                    } else {
-                                        done_write = true;
-                                        debug!("{:?} - flushing", token);
-                                        // If flushing fails, something bad probably happened.
-                                        // If it didn't fail because of a connection error (connection
-                                        // is still alive), it will eventually be flushed by the os
-                                        stream.flush().ok();
-                                        break 'write;
-                                    };
-                                    match stream.write(&data[read_start..]) {
-                                        Ok(n) => {
-                                            bytes_written += n;
-                                            debug!("{:?} - Wrote {} bytes", token, n);
-                                        }
-                                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                                            break 'write
-                                        }
-                                        Err(e) => {
-                                            error!("{:?} - Encountered error while writing to socket: {:?}", token, e);
-                                            // let this socket die, jump to the next event
-                                            done_write = true;
-                                        }
-                                    }
+                if let Socket::Listener {
+                    mut listener,
+                    index,
+                } = sockets.remove(token.into())
+                {
+                    listening -= 1;
+                    if e.is_readable() {
+                        let accepted = listener.accept();
+                        // TCP connections are encrypted when TLS is configured, the
+                        // session is set up right away
+                        #[cfg(feature = "tls")]
+                        let accepted =
+                            accepted.and_then(|(stream, addr)| match (stream, tls.as_ref()) {
+                                (Transport::Plain(sock), Some(config)) => {
+                                    let tls = TlsStream::new(config.clone(), sock)?;
+                                    Ok((Transport::Tls(Box::new(tls)), addr))
                                 }
-                            } else {
-                                debug!("Body not writeable for token {:?}", token);
+                                (stream, _) => Ok((stream, addr)),
+                            });
+                        match accepted {
+                            Ok((mut stream, addr)) => {
+                                debug!("opened socket to: {}", addr);
+                                let excess = self.at_connection_limit(sockets.len() - listening);
+
+                                // register the newly opened socket
+                                let entry = sockets.vacant_entry();
+                                let token = Token(entry.key());
+                                poll.registry()
+                                    .register(&mut stream, token, Interest::READABLE)?;
+                                let watcher = Watcher::new(wakeups.clone(), token, next_conn_id);
+                                let mut conn =
+                                    Connection::new(next_conn_id, stream, index, self, watcher);
+                                next_conn_id += 1;
+                                if excess {
+                                    debug!("{:?} - Over the connection limit, rejecting", token);
+                                    conn.reject(
+                                        &ErrorKind::TooManyConnections(
+                                            "Over the connection limit".into(),
+                                        )
+                                        .into(),
+                                    );
+                                    let close = conn.serve(token, dispatch);
+                                    entry.insert(Socket::Stream(Box::new(conn)));
+                                    update_stream(
+                                        poll.registry(),
+                                        &mut sockets,
+                                        &mut timers,
+                                        token,
+                                        close,
+                                    )?;
+                                } else if let Socket::Stream(ref mut conn) =
+                                    *entry.insert(Socket::Stream(Box::new(conn)))
+                                {
+                                    timers.schedule(token, conn);
+                                }
+                            }
+                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                            Err(e) => {
+                                error!(
+                                    "{:?} - Encountered error while accepting the connection: {:?}",
+                                    token, e
+                                );
+                            }
+                        };
+                    }
+                    if !self.reject_excess_connections
+                        && self.at_connection_limit(sockets.len() - listening)
+                    {
+                        info!("** Connection limit reached, pausing accepts **");
+                        poll.registry().deregister(&mut listener)?;
+                        paused.push((listener, index));
+                        // the other listeners too
+                        let keys = sockets
+                            .iter()
+                            .filter(|&(_, socket)| matches!(*socket, Socket::Listener { .. }))
+                            .map(|(key, _)| key)
+                            .collect::<Vec<_>>();
+                        for key in keys {
+                            if let Socket::Listener {
+                                mut listener,
+                                index,
+                            } = sockets.remove(key)
+                            {
+                                poll.registry().deregister(&mut listener)?;
+                                paused.push((listener, index));
                             }
                         }
+                        listening = 0;
+                        continue;
+                    }
+                    // reregister listener
+                    let entry = sockets.vacant_entry();
+                    let token = Token(entry.key());
+                    poll.registry()
+                        .reregister(&mut listener, token, Interest::READABLE)?;
+                    entry.insert(Socket::new_listener(listener, index));
+                    listening += 1;
+                }
+            }
 
-                        if !done_write {
-                            // we're not done writing our response to this socket yet
-                            // reregister stream
-                            debug!("Write not done, reregister stream for token {:?}", token);
-                            let entry = sockets.vacant_entry();
-                            let token = Token(entry.key());
-                            poll.registry().reregister(
-                                &mut stream,
-                                token,
-                                Interest::READABLE | Interest::WRITABLE,
-                            )?;
-                            entry.insert(Socket::continued_stream(
-                                stream,
-                                reader,
-                                request,
-                                done_reading,
-                                bytes_written,
-                            ));
-                        } else {
-                            debug!("{:?} - Done writing, killing socket", token);
-                            poll.registry().deregister(&mut stream)?;
+            // write the events sent to event streams in the meantime
+            loop {
+                let ready = wakeups.take();
+                if ready.is_empty() {
+                    break;
+                }
+                for (token, conn_id) in ready {
+                    let close = match sockets.get_mut(token.into()) {
+                        Some(&mut Socket::Stream(ref mut conn)) if conn.id == conn_id => {
+                            conn.serve(token, dispatch)
                         }
+                        // closed since
+                        _ => continue,
+                    };
+                    update_stream(poll.registry(), &mut sockets, &mut timers, token, close)?;
+                }
+            }
+
+            // resume accepting once connections have closed
+            if !paused.is_empty() && !self.at_connection_limit(sockets.len()) {
+                info!("** Resuming accepts **");
+                for (mut listener, index) in paused.drain(..) {
+                    let entry = sockets.vacant_entry();
+                    let token = Token(entry.key());
+                    poll.registry()
+                        .register(&mut listener, token, Interest::READABLE)?;
+                    entry.insert(Socket::new_listener(listener, index));
+                    listening += 1;
+                }
+            }
+
+            // close, or answer, the connections that timed out
+            let now = Instant::now();
+            for (token, conn_id) in timers.expired(now) {
+                let close = match sockets.get_mut(token.into()) {
+                    Some(&mut Socket::Stream(ref mut conn))
+                        if conn.id == conn_id && conn.is_expired(now) =>
+                    {
+                        conn.expire(token) || conn.serve(token, dispatch)
                     }
+                    // closed, or its deadline moved since
+                    _ => continue,
+                };
+                update_stream(poll.registry(), &mut sockets, &mut timers, token, close)?;
+            }
+
+            if let Some(deadline) = shutdown.deadline() {
+                if !draining {
+                    info!("** Shutting down, draining connections **");
+                    draining = true;
+                    paused.clear();
+                    listening = 0;
+                    drain(poll.registry(), &mut sockets, &mut timers)?;
                 }
+                // listeners are gone, only connections are left
+                if sockets.is_empty() {
+                    info!("** Server stopped **");
+                    return Ok(());
+                }
+                if shutdown::until(deadline) == Duration::from_secs(0) {
+                    warn!(
+                        "** Shutdown deadline reached, dropping {} connections **",
+                        sockets.len()
+                    );
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Stop accepting connections, close the idle ones and let the others
+/// close once they've answered the requests already received
+fn drain(
+    registry: &mio::Registry,
+    sockets: &mut slab::Slab<Socket>,
+    timers: &mut Timers,
+) -> io::Result<()> {
+    let keys = sockets.iter().map(|(key, _)| key).collect::<Vec<_>>();
+    for key in keys {
+        let token = Token(key);
+        let close = match sockets[key] {
+            Socket::Listener {
+                ref mut listener, ..
+            } => {
+                registry.deregister(listener)?;
+                None
+            }
+            Socket::Stream(ref mut conn) => Some(conn.drain()),
+        };
+        match close {
+            Some(close) => update_stream(registry, sockets, timers, token, close)?,
+            None => {
+                sockets.remove(key);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reregister a stream with the interest matching its state and track its
+/// deadline, or deregister and drop it once the connection should be closed
+fn update_stream(
+    registry: &mio::Registry,
+    sockets: &mut slab::Slab<Socket>,
+    timers: &mut Timers,
+    token: Token,
+    close: bool,
+) -> io::Result<()> {
+    if close {
+        debug!("{:?} - Killing socket", token);
+        timers.cancel(token);
+        if let Socket::Stream(mut conn) = sockets.remove(token.into()) {
+            conn.stream.close();
+            registry.deregister(&mut conn.stream)?;
+            if let Some((callback, upgraded)) = conn.into_upgraded() {
+                callback(upgraded);
             }
         }
+    } else if let Some(&mut Socket::Stream(ref mut conn)) = sockets.get_mut(token.into()) {
+        // we're either waiting on more request bytes, a handler, or not done writing
+        // our responses to this socket yet
+        debug!("Reregister stream for token {:?}", token);
+        let interest = conn.interest();
+        registry.reregister(&mut conn.stream, token, interest)?;
+        timers.schedule(token, conn);
     }
+    Ok(())
 }
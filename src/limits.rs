@@ -0,0 +1,35 @@
+/// Size limits enforced on incoming requests, see
+/// [`Server::limits`](struct.Server.html#method.limits).
+///
+/// Requests going over a limit are answered with `431 Request Header Fields Too Large`,
+/// `414 URI Too Long` or `413 Payload Too Large` and the connection is closed.
+///
+/// ```rust
+/// let limits = mini_http::Limits {
+///     max_body_bytes: 1024 * 1024,
+///     ..mini_http::Limits::default()
+/// };
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Size of the request line and header block, or of the trailers of a
+    /// chunked body. Default: `4096`
+    pub max_header_bytes: usize,
+    /// Number of header fields. Default: `64`
+    pub max_headers: usize,
+    /// Size of the body, after decoding a chunked body. Default: `16 MiB`
+    pub max_body_bytes: usize,
+    /// Length of the request target. Default: `2048`
+    pub max_uri_length: usize,
+}
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            // relevant: https://stackoverflow.com/questions/686217/maximum-on-http-header-values#686243
+            max_header_bytes: 4096,
+            max_headers: 64,
+            max_body_bytes: 16 * 1024 * 1024,
+            max_uri_length: 2048,
+        }
+    }
+}
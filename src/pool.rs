@@ -0,0 +1,181 @@
+use mio::Token;
+#[cfg(not(target_os = "wasi"))]
+use std;
+#[cfg(not(target_os = "wasi"))]
+use std::io;
+#[cfg(not(target_os = "wasi"))]
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(not(target_os = "wasi"))]
+use std::sync::{mpsc, Arc, Mutex};
+#[cfg(not(target_os = "wasi"))]
+use std::thread;
+
+use {Body, Request, Response};
+
+/// Identifies the connection, and the slot in its response queue, that a
+/// handler's response belongs to
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Ticket {
+    pub token: Token,
+    pub conn_id: u64,
+    pub seq: u64,
+}
+
+/// How parsed requests are handed to the handler
+pub(crate) trait Dispatch {
+    /// Hand `request` to the handler. Returns the response when the handler ran
+    /// right away, otherwise it's returned by `completed` after the event loop
+    /// has been woken up
+    fn dispatch(&self, ticket: Ticket, request: Request) -> Option<Response<Body>>;
+
+    /// Responses of handlers that have finished since the last call
+    fn completed(&self) -> Vec<(Ticket, Response<Body>)> {
+        Vec::new()
+    }
+}
+
+/// Runs the handler synchronously on the event loop
+pub(crate) struct Inline<F>(pub F);
+impl<F> Dispatch for Inline<F>
+where
+    F: Fn(Request) -> Response<Body>,
+{
+    fn dispatch(&self, _ticket: Ticket, request: Request) -> Option<Response<Body>> {
+        Some((self.0)(request))
+    }
+}
+
+/// A request for the worker threads, along with the index of the reactor
+/// (event loop) its connection belongs to
+#[cfg(not(target_os = "wasi"))]
+type Job = (usize, Ticket, Request);
+
+/// Responses of finished handlers, waiting to be picked up by the event loop
+#[cfg(not(target_os = "wasi"))]
+type Completed = Vec<(Ticket, Response<Body>)>;
+
+/// Finished responses for one reactor and the waker notifying it
+#[cfg(not(target_os = "wasi"))]
+struct Completions {
+    done: Mutex<Completed>,
+    waker: Arc<mio::Waker>,
+}
+#[cfg(not(target_os = "wasi"))]
+impl Completions {
+    fn push(&self, ticket: Ticket, response: Response<Body>) {
+        let wake = {
+            let mut done = self.done.lock().unwrap();
+            done.push((ticket, response));
+            // the loop drains everything in one go, only wake it once
+            done.len() == 1
+        };
+        if wake {
+            if let Err(e) = self.waker.wake() {
+                error!("Failed to wake the event loop: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Runs the handler on a fixed number of worker threads, shared by all reactors.
+/// Finished responses are collected per reactor, which is notified through
+/// its `mio::Waker`
+#[cfg(not(target_os = "wasi"))]
+pub(crate) struct WorkerPool {
+    jobs: Mutex<Option<mpsc::Sender<Job>>>,
+    completions: Arc<Vec<Completions>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+#[cfg(not(target_os = "wasi"))]
+impl WorkerPool {
+    /// Start `threads` workers, `wakers` holds the waker of each reactor
+    pub fn new<F>(threads: usize, func: F, wakers: Vec<Arc<mio::Waker>>) -> io::Result<Self>
+    where
+        F: Fn(Request) -> Response<Body> + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let completions = Arc::new(
+            wakers
+                .into_iter()
+                .map(|waker| Completions {
+                    done: Mutex::new(Vec::new()),
+                    waker,
+                })
+                .collect::<Vec<_>>(),
+        );
+        let func = Arc::new(func);
+
+        let mut workers = Vec::with_capacity(threads);
+        for n in 0..threads {
+            let receiver = receiver.clone();
+            let completions = completions.clone();
+            let func = func.clone();
+            let worker = thread::Builder::new()
+                .name(format!("mini_http-worker-{}", n))
+                .spawn(move || loop {
+                    // the sender is dropped when the pool is, which ends the worker
+                    let job = receiver.lock().unwrap().recv();
+                    let (reactor, ticket, request) = match job {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    let response = panic::catch_unwind(AssertUnwindSafe(|| func(request)))
+                        .unwrap_or_else(|_| {
+                            error!("{:?} - Handler panicked", ticket.token);
+                            Response::builder()
+                                .status(500)
+                                .body(b"internal server error".to_vec().into())
+                                .unwrap()
+                        });
+                    completions[reactor].push(ticket, response);
+                })?;
+            workers.push(worker);
+        }
+        Ok(Self {
+            jobs: Mutex::new(Some(sender)),
+            completions,
+            workers,
+        })
+    }
+
+    /// `Dispatch` requests of the reactor numbered `reactor` to this pool
+    pub fn dispatcher(&self, reactor: usize) -> PoolDispatch<'_> {
+        PoolDispatch {
+            pool: self,
+            jobs: self.jobs.lock().unwrap().clone(),
+            reactor,
+        }
+    }
+}
+#[cfg(not(target_os = "wasi"))]
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.jobs.lock().unwrap().take();
+        for worker in self.workers.drain(..) {
+            worker.join().ok();
+        }
+    }
+}
+
+/// Hands the requests of one reactor to a `WorkerPool`
+#[cfg(not(target_os = "wasi"))]
+pub(crate) struct PoolDispatch<'a> {
+    pool: &'a WorkerPool,
+    jobs: Option<mpsc::Sender<Job>>,
+    reactor: usize,
+}
+#[cfg(not(target_os = "wasi"))]
+impl<'a> Dispatch for PoolDispatch<'a> {
+    fn dispatch(&self, ticket: Ticket, request: Request) -> Option<Response<Body>> {
+        if let Some(ref jobs) = self.jobs {
+            // workers only go away when the pool is dropped
+            jobs.send((self.reactor, ticket, request)).ok();
+        }
+        None
+    }
+
+    fn completed(&self) -> Vec<(Ticket, Response<Body>)> {
+        std::mem::take(&mut *self.pool.completions[self.reactor].done.lock().unwrap())
+    }
+}
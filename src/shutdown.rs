@@ -0,0 +1,119 @@
+use std;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+#[cfg(not(target_os = "wasi"))]
+use std::thread;
+use std::time::{Duration, Instant};
+
+use errors::*;
+
+/// Shutdown state shared between a server's event loops and whatever stops them
+pub(crate) struct Shutdown {
+    triggered: AtomicBool,
+    deadline: Mutex<Option<Instant>>,
+    #[cfg(not(target_os = "wasi"))]
+    wakers: Mutex<Vec<Arc<mio::Waker>>>,
+}
+impl Shutdown {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            triggered: AtomicBool::new(false),
+            deadline: Mutex::new(None),
+            #[cfg(not(target_os = "wasi"))]
+            wakers: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Register the waker of an event loop to notify on shutdown
+    #[cfg(not(target_os = "wasi"))]
+    pub fn add_waker(&self, waker: Arc<mio::Waker>) {
+        self.wakers.lock().unwrap().push(waker);
+    }
+
+    /// Stop accepting connections and give in-flight requests until `deadline` to
+    /// finish. Triggering again can only bring the deadline closer
+    pub fn trigger(&self, deadline: Instant) {
+        {
+            let mut current = self.deadline.lock().unwrap();
+            *current = Some(match *current {
+                Some(current) if current < deadline => current,
+                _ => deadline,
+            });
+        }
+        self.triggered.store(true, Ordering::SeqCst);
+        #[cfg(not(target_os = "wasi"))]
+        for waker in self.wakers.lock().unwrap().iter() {
+            if let Err(e) = waker.wake() {
+                error!("Failed to wake the event loop: {:?}", e);
+            }
+        }
+    }
+
+    /// The deadline for in-flight requests, once shutdown has been triggered
+    pub fn deadline(&self) -> Option<Instant> {
+        if !self.triggered.load(Ordering::SeqCst) {
+            return None;
+        }
+        *self.deadline.lock().unwrap()
+    }
+}
+
+/// Handle to a server running in the background, returned by
+/// [`Server::spawn`](struct.Server.html#method.spawn).
+///
+/// Dropping the handle stops the server immediately, use
+/// [`shutdown`](#method.shutdown) to let in-flight requests finish first
+#[cfg(not(target_os = "wasi"))]
+pub struct ServerHandle {
+    pub(crate) shutdown: Arc<Shutdown>,
+    pub(crate) thread: Option<thread::JoinHandle<Result<()>>>,
+    pub(crate) local_addrs: Vec<Option<std::net::SocketAddr>>,
+}
+#[cfg(not(target_os = "wasi"))]
+impl ServerHandle {
+    /// The address the server was initialized with. Useful when binding to port `0`.
+    /// `None` for a server running on a Unix socket
+    pub fn local_addr(&self) -> Option<std::net::SocketAddr> {
+        self.local_addrs[0]
+    }
+
+    /// The addresses of all the listeners of the server, in the order of
+    /// `Request::listener`. `None` for the Unix sockets
+    pub fn local_addrs(&self) -> &[Option<std::net::SocketAddr>] {
+        &self.local_addrs
+    }
+
+    /// Stop accepting new connections and let in-flight requests finish writing
+    /// their responses for up to `grace`. Idle connections are closed right away,
+    /// connections still busy once `grace` has passed are dropped.
+    /// Returns once the server has stopped
+    pub fn shutdown(mut self, grace: Duration) -> Result<()> {
+        self.shutdown.trigger(Instant::now() + grace);
+        self.join()
+    }
+
+    fn join(&mut self) -> Result<()> {
+        match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .unwrap_or_else(|_| bail!("Server thread panicked")),
+            None => Ok(()),
+        }
+    }
+}
+#[cfg(not(target_os = "wasi"))]
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            self.shutdown.trigger(Instant::now());
+            if let Err(e) = self.join() {
+                error!("Server stopped with an error: {}", e);
+            }
+        }
+    }
+}
+
+/// Remaining time until `deadline`, for use as a `mio::Poll` timeout
+pub(crate) fn until(deadline: Instant) -> Duration {
+    deadline.saturating_duration_since(Instant::now())
+}
@@ -0,0 +1,90 @@
+use libc;
+use mio::unix::pipe;
+use mio::{Interest, Registry, Token};
+use std;
+use std::io::{self, Read};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// Write end of the self-pipe the signal handler reports signals through,
+/// `-1` while no server is handling signals
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// Signals a server shuts down on
+const SIGNALS: [libc::c_int; 2] = [libc::SIGTERM, libc::SIGINT];
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    let fd = SIGNAL_PIPE.load(Ordering::SeqCst);
+    if fd >= 0 {
+        // only async-signal-safe calls in here, the event loop does the rest.
+        // The pipe is non-blocking, if it's full a wake up is pending anyway
+        let byte = signal as u8;
+        unsafe {
+            libc::write(fd, &byte as *const u8 as *const libc::c_void, 1);
+        }
+    }
+}
+
+/// Self-pipe receiving `SIGTERM` and `SIGINT`, registered with an event loop's
+/// `mio::Poll`. Only one set of handlers exists per process, the default
+/// handlers are restored once this is dropped
+pub(crate) struct Signals {
+    receiver: pipe::Receiver,
+    sender: pipe::Sender,
+}
+impl Signals {
+    pub fn install(registry: &Registry, token: Token) -> io::Result<Self> {
+        let (sender, mut receiver) = pipe::new()?;
+        registry.register(&mut receiver, token, Interest::READABLE)?;
+        if SIGNAL_PIPE.swap(sender.as_raw_fd(), Ordering::SeqCst) >= 0 {
+            warn!("Signal handlers were already installed by another server, replacing them");
+        }
+        for &signal in &SIGNALS {
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+                if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+        Ok(Self { receiver, sender })
+    }
+
+    /// Signals received since the last call
+    pub fn received(&mut self) -> Vec<libc::c_int> {
+        let mut signals = Vec::new();
+        let mut buf = [0; 16];
+        loop {
+            match self.receiver.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => signals.extend(buf[..n].iter().map(|&b| libc::c_int::from(b))),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    error!("Failed to read from the signal pipe: {:?}", e);
+                    break;
+                }
+            }
+        }
+        signals
+    }
+}
+impl Drop for Signals {
+    fn drop(&mut self) {
+        // leave the handlers alone if another server has taken them over since
+        let fd = self.sender.as_raw_fd();
+        if SIGNAL_PIPE
+            .compare_exchange(fd, -1, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            for &signal in &SIGNALS {
+                unsafe {
+                    libc::signal(signal, libc::SIG_DFL);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,340 @@
+//! Server-Sent Events (`text/event-stream`) responses. Their events are sent
+//! through an `EventStream` handle, from any thread, for as long as the client
+//! stays connected, and written out by the event loop of the connection.
+use mio::Token;
+use std;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use {header, Body, Response};
+
+/// Written when no event was sent for a while, so the connection isn't taken
+/// for dead by proxies
+const KEEP_ALIVE_COMMENT: &[u8] = b":\n\n";
+
+/// An event sent to the client of an event stream, see `EventStream::send`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Event {
+    event: Option<String>,
+    id: Option<String>,
+    retry: Option<Duration>,
+    data: String,
+}
+impl Event {
+    /// An event carrying `data`, which may span several lines
+    pub fn data<S: Into<String>>(data: S) -> Self {
+        Self {
+            data: data.into(),
+            ..Self::default()
+        }
+    }
+
+    /// Set the type of the event, clients hand it to the listeners of that type
+    /// instead of `message`
+    pub fn event<S: Into<String>>(mut self, event: S) -> Self {
+        self.event = Some(event.into());
+        self
+    }
+
+    /// Set the id of the event, a client reconnecting sends back the last one
+    /// it received, see `Request::last_event_id`
+    pub fn id<S: Into<String>>(mut self, id: S) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Set how long clients wait before reconnecting once the connection is lost
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub(crate) fn serialize(&self, buf: &mut Vec<u8>) {
+        if let Some(ref event) = self.event {
+            push_field(buf, "event", event);
+        }
+        if let Some(ref id) = self.id {
+            push_field(buf, "id", id);
+        }
+        if let Some(retry) = self.retry {
+            push_field(buf, "retry", &retry.as_millis().to_string());
+        }
+        // any line break ends a field, each line gets its own
+        for line in self.data.replace("\r\n", "\n").split(['\r', '\n']) {
+            push_field(buf, "data", line);
+        }
+        buf.push(b'\n');
+    }
+}
+
+/// Queue a field, dropping the line breaks a single line value can't hold
+fn push_field(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.extend_from_slice(name.as_bytes());
+    buf.extend_from_slice(b": ");
+    buf.extend(value.bytes().filter(|&b| b != b'\r' && b != b'\n'));
+    buf.push(b'\n');
+}
+
+/// What became of events offered to an event stream, see `EventStream::offer`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Offer {
+    Queued,
+    /// The stream fell too far behind and was closed
+    Overflowed,
+    /// The client went away, or the stream was closed
+    Closed,
+}
+
+/// State of an event stream, shared by its `EventStream` handles and its response
+#[derive(Default)]
+struct State {
+    /// Serialized events waiting to be written
+    queue: Vec<u8>,
+    /// Set once the stream was closed, or all of its handles dropped
+    closed: bool,
+    /// Set once the response went away, along with its connection
+    gone: bool,
+    /// Number of `EventStream` handles
+    senders: usize,
+    /// Wakes the event loop of the connection, once the response is ready to be written
+    watcher: Option<Watcher>,
+    /// Set once the event loop was woken up, until it takes the queue
+    notified: bool,
+}
+impl State {
+    /// Have the event loop write what's new
+    fn notify(&mut self) {
+        if self.notified {
+            return;
+        }
+        if let Some(ref watcher) = self.watcher {
+            self.notified = true;
+            watcher.notify();
+        }
+    }
+}
+
+/// Sends the events of an event stream response to its client, from any thread.
+///
+/// Clones send to the same client. The response ends once the stream is closed,
+/// or all of its handles are dropped.
+///
+/// ```rust,no_run
+/// # fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// mini_http::Server::new("127.0.0.1:3000")?
+///     .start(|request| {
+///         let (events, response) = mini_http::EventStream::response();
+///         let from = request.last_event_id().and_then(|id| id.parse().ok()).unwrap_or(0);
+///         std::thread::spawn(move || {
+///             for n in from + 1.. {
+///                 let event = mini_http::Event::data(format!("tick {}", n)).id(n.to_string());
+///                 if !events.send(event) {
+///                     break;
+///                 }
+///                 std::thread::sleep(std::time::Duration::from_secs(1));
+///             }
+///         });
+///         response
+///     })?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct EventStream {
+    state: Arc<Mutex<State>>,
+}
+impl EventStream {
+    /// A `text/event-stream` response, along with the handle sending its events
+    pub fn response() -> (Self, Response<Body>) {
+        let state = Arc::new(Mutex::new(State {
+            senders: 1,
+            ..State::default()
+        }));
+        let response = Response::builder()
+            .status(200)
+            .header(header::CONTENT_TYPE, "text/event-stream")
+            .header(header::CACHE_CONTROL, "no-cache")
+            .body(Body::from_events(Receiver(state.clone())))
+            .unwrap();
+        (Self { state }, response)
+    }
+
+    /// Send `event` to the client. Returns `false` once the client went away,
+    /// or the stream was closed
+    pub fn send(&self, event: Event) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.closed || state.gone {
+            return false;
+        }
+        event.serialize(&mut state.queue);
+        state.notify();
+        true
+    }
+
+    /// End the response once the events already sent are written
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.notify();
+    }
+
+    /// Whether the client went away, or the stream was closed
+    pub fn is_closed(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.closed || state.gone
+    }
+
+    /// Queue serialized events, unless the events not yet written would then take
+    /// more than `limit` bytes. The stream is closed instead, dropping them
+    pub(crate) fn offer(&self, events: &[u8], limit: usize) -> Offer {
+        let mut state = self.state.lock().unwrap();
+        if state.closed || state.gone {
+            return Offer::Closed;
+        }
+        if state.queue.len() + events.len() > limit {
+            state.queue.clear();
+            state.closed = true;
+            state.notify();
+            return Offer::Overflowed;
+        }
+        state.queue.extend_from_slice(events);
+        state.notify();
+        Offer::Queued
+    }
+}
+impl Clone for EventStream {
+    fn clone(&self) -> Self {
+        self.state.lock().unwrap().senders += 1;
+        Self {
+            state: self.state.clone(),
+        }
+    }
+}
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            state.closed = true;
+            state.notify();
+        }
+    }
+}
+
+/// The response side of an event stream, the body of its response
+pub(crate) struct Receiver(Arc<Mutex<State>>);
+impl Receiver {
+    /// Wake the event loop through `watcher` whenever there's something new to write
+    pub fn watch(&self, watcher: Watcher) {
+        self.0.lock().unwrap().watcher = Some(watcher);
+    }
+
+    /// Whether there's nothing to write until more events are sent
+    pub fn is_waiting(&self) -> bool {
+        let state = self.0.lock().unwrap();
+        state.queue.is_empty() && !state.closed
+    }
+
+    /// Take the events sent so far, possibly none. `None` once the stream is
+    /// closed and they've all been taken
+    pub fn next_chunk(&self) -> Option<Vec<u8>> {
+        let mut state = self.0.lock().unwrap();
+        state.notified = false;
+        if state.queue.is_empty() && state.closed {
+            return None;
+        }
+        Some(std::mem::take(&mut state.queue))
+    }
+
+    /// Queue a comment if there's nothing else to write
+    pub fn keep_alive(&self) {
+        let mut state = self.0.lock().unwrap();
+        if state.queue.is_empty() && !state.closed {
+            state.queue.extend_from_slice(KEEP_ALIVE_COMMENT);
+        }
+    }
+
+    /// End the response once the events already sent are written, when the
+    /// server shuts down
+    pub fn close(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.closed = true;
+        state.notify();
+    }
+}
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().gone = true;
+    }
+}
+
+/// Identifies the connection an event stream, or a WebSocket sender, belongs to,
+/// and the event loop to wake
+#[derive(Clone)]
+pub(crate) struct Watcher {
+    wakeups: Arc<Wakeups>,
+    token: Token,
+    conn_id: u64,
+}
+impl Watcher {
+    pub fn new(wakeups: Arc<Wakeups>, token: Token, conn_id: u64) -> Self {
+        Self {
+            wakeups,
+            token,
+            conn_id,
+        }
+    }
+
+    /// Have the event loop serve the connection
+    pub fn notify(&self) {
+        self.wakeups.push(self.token, self.conn_id);
+    }
+}
+
+/// Connections with new events to write, collected for one event loop. Events
+/// sent from other threads wake it up through its `mio::Waker`
+pub(crate) struct Wakeups {
+    ready: Mutex<Vec<(Token, u64)>>,
+    #[cfg(not(target_os = "wasi"))]
+    waker: Arc<mio::Waker>,
+}
+impl Wakeups {
+    #[cfg(not(target_os = "wasi"))]
+    pub fn new(waker: Arc<mio::Waker>) -> Arc<Self> {
+        Arc::new(Self {
+            ready: Mutex::new(Vec::new()),
+            waker,
+        })
+    }
+
+    /// Without threads, events can only be sent from the event loop itself
+    #[cfg(target_os = "wasi")]
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            ready: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn push(&self, token: Token, conn_id: u64) {
+        let wake = {
+            let mut ready = self.ready.lock().unwrap();
+            ready.push((token, conn_id));
+            // the loop takes everything in one go, only wake it once
+            ready.len() == 1
+        };
+        #[cfg(not(target_os = "wasi"))]
+        {
+            if wake {
+                if let Err(e) = self.waker.wake() {
+                    error!("Failed to wake the event loop: {:?}", e);
+                }
+            }
+        }
+        #[cfg(target_os = "wasi")]
+        let _ = wake;
+    }
+
+    /// Connections, by token and id, with something new to write since the last call
+    pub fn take(&self) -> Vec<(Token, u64)> {
+        std::mem::take(&mut *self.ready.lock().unwrap())
+    }
+}
@@ -0,0 +1,168 @@
+use mio::Token;
+use std;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::time::{Duration, Instant};
+
+use connection::Connection;
+
+/// What a connection is waiting on, each with its own timeout
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Phase {
+    /// Waiting for the first byte of the next request
+    Idle,
+    /// Receiving the header block of a request
+    Header,
+    /// Receiving the body of a request
+    Body,
+    /// Writing responses back
+    Write,
+    /// Waiting for the next events of event stream responses, a comment is written
+    /// to keep them alive once it times out
+    Events,
+}
+
+/// Timeouts of each `Phase`, `None` disables one
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Timeouts {
+    pub header: Option<Duration>,
+    pub body: Option<Duration>,
+    pub idle: Option<Duration>,
+    pub write: Option<Duration>,
+    pub events: Option<Duration>,
+}
+impl Timeouts {
+    pub fn get(&self, phase: Phase) -> Option<Duration> {
+        match phase {
+            Phase::Idle => self.idle,
+            Phase::Header => self.header,
+            Phase::Body => self.body,
+            Phase::Write => self.write,
+            Phase::Events => self.events,
+        }
+    }
+}
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            header: Some(Duration::from_secs(30)),
+            body: Some(Duration::from_secs(30)),
+            idle: Some(Duration::from_secs(60)),
+            write: Some(Duration::from_secs(60)),
+            events: Some(Duration::from_secs(15)),
+        }
+    }
+}
+
+/// Deadlines of an event loop's connections, earliest first.
+/// Entries aren't removed when a connection's deadline moves, they're skipped
+/// once they come up and no longer match the connection's current deadline.
+/// The heap is rebuilt without them once they outnumber the current ones
+pub(crate) struct Timers {
+    timeouts: Timeouts,
+    heap: BinaryHeap<Reverse<(Instant, usize, u64)>>,
+    /// Current deadline of each connection, by token, with the connection's id
+    current: HashMap<usize, (u64, Instant)>,
+}
+impl Timers {
+    pub fn new(timeouts: Timeouts) -> Self {
+        Self {
+            timeouts,
+            heap: BinaryHeap::new(),
+            current: HashMap::new(),
+        }
+    }
+
+    /// Track the deadline of the connection at `token`, after it made progress
+    pub fn schedule(&mut self, token: Token, conn: &mut Connection) {
+        if let Some(deadline) = conn.update_deadline(&self.timeouts) {
+            self.insert(token.0, conn.id, deadline);
+        }
+    }
+
+    /// Stop tracking the connection at `token`, once it's closed
+    pub fn cancel(&mut self, token: Token) {
+        self.current.remove(&token.0);
+    }
+
+    /// The earliest deadline, for use as the `mio::Poll` timeout
+    pub fn next_deadline(&mut self) -> Option<Instant> {
+        self.skip_stale();
+        self.heap.peek().map(|&Reverse((deadline, _, _))| deadline)
+    }
+
+    /// Connections, by token and id, whose deadline may have passed by `now`
+    pub fn expired(&mut self, now: Instant) -> Vec<(Token, u64)> {
+        let mut expired = Vec::new();
+        loop {
+            self.skip_stale();
+            match self.heap.peek() {
+                Some(&Reverse((deadline, token, conn_id))) if deadline <= now => {
+                    self.heap.pop();
+                    self.current.remove(&token);
+                    expired.push((Token(token), conn_id));
+                }
+                _ => return expired,
+            }
+        }
+    }
+
+    fn insert(&mut self, token: usize, conn_id: u64, deadline: Instant) {
+        if self.current.insert(token, (conn_id, deadline)) == Some((conn_id, deadline)) {
+            // already in the heap
+            return;
+        }
+        self.heap.push(Reverse((deadline, token, conn_id)));
+        if self.heap.len() > 2 * self.current.len() + COMPACT_SLACK {
+            let current = &self.current;
+            let mut entries = std::mem::take(&mut self.heap).into_vec();
+            entries.retain(|&Reverse(entry)| is_current(current, entry));
+            self.heap = entries.into();
+        }
+    }
+
+    /// Drop the stale entries at the top of the heap
+    fn skip_stale(&mut self) {
+        while let Some(&Reverse(entry)) = self.heap.peek() {
+            if is_current(&self.current, entry) {
+                return;
+            }
+            self.heap.pop();
+        }
+    }
+}
+
+/// Stale entries tolerated on top of as many as there are current ones
+const COMPACT_SLACK: usize = 64;
+
+/// Whether a heap entry is the current deadline of its connection
+fn is_current(current: &HashMap<usize, (u64, Instant)>, entry: (Instant, usize, u64)) -> bool {
+    let (deadline, token, conn_id) = entry;
+    current.get(&token) == Some(&(conn_id, deadline))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moved_deadlines_dont_pile_up() {
+        let mut timers = Timers::new(Timeouts::default());
+        let start = Instant::now();
+        for i in 0..10_000 {
+            // a connection whose deadline keeps moving, and one that doesn't
+            timers.insert(1, 7, start + Duration::from_millis(i));
+            timers.insert(2, 8, start);
+        }
+        assert!(timers.heap.len() <= 2 * 2 + COMPACT_SLACK);
+        assert_eq!(timers.next_deadline(), Some(start));
+
+        let later = start + Duration::from_secs(20);
+        assert_eq!(timers.expired(later), vec![(Token(2), 8), (Token(1), 7)]);
+        assert_eq!(timers.next_deadline(), None);
+
+        timers.insert(3, 9, start);
+        timers.cancel(Token(3));
+        assert!(timers.expired(later).is_empty());
+    }
+}
@@ -0,0 +1,402 @@
+use mio::net::TcpStream;
+use rustls;
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello, NoClientAuth,
+    ResolvesServerCert,
+};
+use rustls::sign::CertifiedKey;
+use rustls_pemfile;
+use std;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use errors::*;
+
+/// Certificates and keys the server presents to clients, see `Server::tls`
+/// and `Server::tls_sni`
+#[derive(Clone, Default)]
+pub(crate) struct TlsConfig {
+    /// Presented when no server name below matches the client's SNI
+    default: Option<Arc<CertifiedKey>>,
+    /// Keyed by lowercase server name
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    /// CAs client certificates are verified against, and whether clients must
+    /// present one
+    client_auth: Option<(rustls::RootCertStore, bool)>,
+}
+impl TlsConfig {
+    /// Use the certificate chain and private key in the PEM files as the default
+    pub fn set_default(&mut self, cert_path: &str, key_path: &str) -> Result<()> {
+        self.default = Some(load_certified_key(cert_path, key_path)?);
+        Ok(())
+    }
+
+    /// Present the certificate chain and private key in the PEM files to clients
+    /// asking for `server_name` through SNI
+    pub fn add_server_name(
+        &mut self,
+        server_name: &str,
+        cert_path: &str,
+        key_path: &str,
+    ) -> Result<()> {
+        let key = load_certified_key(cert_path, key_path)?;
+        self.by_name.insert(server_name.to_lowercase(), key);
+        Ok(())
+    }
+
+    /// Verify client certificates against the CA certificates in the PEM file,
+    /// turning down clients without one if `required`
+    pub fn set_client_auth(&mut self, ca_path: &str, required: bool) -> Result<()> {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in load_certs(ca_path)? {
+            roots.add(&cert).map_err(|e| {
+                format_err!(
+                    ErrorKind::TlsConfig,
+                    "Invalid CA certificate in {}: {}",
+                    ca_path,
+                    e
+                )
+            })?;
+        }
+        self.client_auth = Some((roots, required));
+        Ok(())
+    }
+
+    /// The rustls configuration of the reactors' connections, offering `h2`
+    /// through ALPN if `http2` is enabled
+    pub fn server_config(&self, http2: bool) -> Arc<rustls::ServerConfig> {
+        let verifier = match self.client_auth {
+            Some((ref roots, true)) => AllowAnyAuthenticatedClient::new(roots.clone()).boxed(),
+            Some((ref roots, false)) => {
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots.clone()).boxed()
+            }
+            None => NoClientAuth::boxed(),
+        };
+        let mut config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(verifier)
+            .with_cert_resolver(Arc::new(self.clone()));
+        if http2 {
+            config.alpn_protocols.push(b"h2".to_vec());
+        }
+        config.alpn_protocols.push(b"http/1.1".to_vec());
+        Arc::new(config)
+    }
+}
+impl ResolvesServerCert for TlsConfig {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        client_hello
+            .server_name()
+            .and_then(|name| self.by_name.get(&name.to_lowercase()))
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
+
+/// What was negotiated during the TLS handshake of the connection a request
+/// came in on, stored in the request extensions. See `Request::tls`
+#[derive(Clone, Debug)]
+pub struct TlsInfo {
+    server_name: Option<String>,
+    alpn_protocol: Option<Vec<u8>>,
+    peer_certificates: Arc<Vec<Vec<u8>>>,
+    peer_subject: Option<String>,
+}
+impl TlsInfo {
+    /// The host name the client asked for through SNI, if any
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
+    /// The protocol agreed on through ALPN, e.g. `b"http/1.1"`, if the client
+    /// offered any
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.alpn_protocol.as_deref()
+    }
+
+    /// The DER encoded certificate chain the client presented, its own
+    /// certificate first. Only set when client certificates are verified, see
+    /// `Server::tls_client_auth`, and empty if the client didn't present one
+    pub fn peer_certificates(&self) -> &[Vec<u8>] {
+        &self.peer_certificates
+    }
+
+    /// The subject of the client's certificate as an RFC 4514 string,
+    /// e.g. `CN=client,O=Example`
+    pub fn peer_subject(&self) -> Option<&str> {
+        self.peer_subject.as_deref()
+    }
+}
+
+/// Render the subject of a DER encoded certificate, see `TlsInfo::peer_subject`
+fn subject(cert: &[u8]) -> Option<String> {
+    // Certificate ::= SEQUENCE { tbsCertificate SEQUENCE { [0] version OPTIONAL,
+    //     serialNumber, signature, issuer, validity, subject, ... }, ... }
+    let (_, cert, _) = der(cert)?;
+    let (_, mut tbs, _) = der(cert)?;
+    let mut fields = 0;
+    loop {
+        let (tag, value, rest) = der(tbs)?;
+        tbs = rest;
+        if tag == 0xa0 {
+            continue;
+        }
+        fields += 1;
+        if fields == 5 {
+            return name(value);
+        }
+    }
+}
+
+/// Render an X.501 `Name`, a sequence of sets of attribute type and value
+/// pairs, most specific first as RFC 4514 has it
+fn name(mut rdns: &[u8]) -> Option<String> {
+    let mut rendered = Vec::new();
+    while !rdns.is_empty() {
+        let (_, mut set, rest) = der(rdns)?;
+        rdns = rest;
+        let mut attributes = Vec::new();
+        while !set.is_empty() {
+            let (_, attribute, rest) = der(set)?;
+            set = rest;
+            let (_, oid, value) = der(attribute)?;
+            let (_, value, _) = der(value)?;
+            attributes.push(format!("{}={}", attribute_type(oid), escape(value)));
+        }
+        rendered.push(attributes.join("+"));
+    }
+    rendered.reverse();
+    Some(rendered.join(","))
+}
+
+/// The short name of an attribute type, or its dotted OID
+fn attribute_type(oid: &[u8]) -> String {
+    match oid {
+        [0x55, 0x04, 0x03] => "CN".into(),
+        [0x55, 0x04, 0x06] => "C".into(),
+        [0x55, 0x04, 0x07] => "L".into(),
+        [0x55, 0x04, 0x08] => "ST".into(),
+        [0x55, 0x04, 0x09] => "STREET".into(),
+        [0x55, 0x04, 0x0a] => "O".into(),
+        [0x55, 0x04, 0x0b] => "OU".into(),
+        [0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x19] => "DC".into(),
+        [0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x01] => "UID".into(),
+        _ => {
+            let mut arcs = Vec::new();
+            let mut arc = 0u64;
+            for &b in oid {
+                arc = (arc << 7) | u64::from(b & 0x7f);
+                if b & 0x80 != 0 {
+                    continue;
+                }
+                // the first subidentifier packs the first two arcs
+                if arcs.is_empty() {
+                    let first = std::cmp::min(arc / 40, 2);
+                    arcs.push(first);
+                    arcs.push(arc - first * 40);
+                } else {
+                    arcs.push(arc);
+                }
+                arc = 0;
+            }
+            let arcs: Vec<String> = arcs.iter().map(u64::to_string).collect();
+            arcs.join(".")
+        }
+    }
+}
+
+/// Escape an attribute value as RFC 4514 asks
+fn escape(value: &[u8]) -> String {
+    let value = String::from_utf8_lossy(value);
+    let last = value.chars().count().saturating_sub(1);
+    let mut escaped = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' => escaped.push('\\'),
+            '#' | ' ' if i == 0 => escaped.push('\\'),
+            ' ' if i == last => escaped.push('\\'),
+            _ => {}
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Split the DER element at the start of `data` into its tag, its contents and
+/// the bytes following it
+fn der(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.first()?;
+    let first = *data.get(1)?;
+    let (len, start) = if first & 0x80 == 0 {
+        (first as usize, 2)
+    } else {
+        let octets = (first & 0x7f) as usize;
+        if octets == 0 || octets > std::mem::size_of::<usize>() {
+            return None;
+        }
+        let len = data
+            .get(2..2 + octets)?
+            .iter()
+            .fold(0usize, |len, &b| (len << 8) | b as usize);
+        (len, 2 + octets)
+    };
+    let end = start.checked_add(len)?;
+    Some((tag, data.get(start..end)?, &data[end..]))
+}
+
+fn load_certified_key(cert_path: &str, key_path: &str) -> Result<Arc<CertifiedKey>> {
+    let cert_chain = load_certs(cert_path)?;
+    let key = rustls::sign::any_supported_type(&load_key(key_path)?)
+        .map_err(|e| format_err!(ErrorKind::TlsConfig, "Invalid key in {}: {}", key_path, e))?;
+    Ok(Arc::new(CertifiedKey::new(cert_chain, key)))
+}
+
+fn open_pem(path: &str) -> Result<io::BufReader<std::fs::File>> {
+    let file = std::fs::File::open(path)
+        .chain_err(|| format_err!(ErrorKind::TlsConfig, "Failed to open {}", path))?;
+    Ok(io::BufReader::new(file))
+}
+
+fn load_certs(path: &str) -> Result<Vec<rustls::Certificate>> {
+    let certs = rustls_pemfile::certs(&mut open_pem(path)?)
+        .chain_err(|| format_err!(ErrorKind::TlsConfig, "Failed to read {}", path))?;
+    if certs.is_empty() {
+        bail_fmt!(ErrorKind::TlsConfig, "No certificate found in {}", path);
+    }
+    Ok(certs.into_iter().map(rustls::Certificate).collect())
+}
+
+fn load_key(path: &str) -> Result<rustls::PrivateKey> {
+    let mut reader = open_pem(path)?;
+    loop {
+        let item = rustls_pemfile::read_one(&mut reader)
+            .chain_err(|| format_err!(ErrorKind::TlsConfig, "Failed to read {}", path))?;
+        match item {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(rustls::PrivateKey(key)),
+            Some(_) => continue,
+            None => bail_fmt!(ErrorKind::TlsConfig, "No private key found in {}", path),
+        }
+    }
+}
+
+/// A TLS session over a non-blocking socket. The handshake happens as the
+/// connection is read from and written to, like any other TLS record
+pub(crate) struct TlsStream {
+    session: rustls::ServerConnection,
+    tcp: TcpStream,
+    /// Decrypted bytes held by the session, not read yet
+    plaintext: usize,
+    /// Filled in the first time a request asks for it, the handshake is over by then
+    info: Option<TlsInfo>,
+}
+impl TlsStream {
+    pub fn new(config: Arc<rustls::ServerConfig>, tcp: TcpStream) -> io::Result<Self> {
+        let session = rustls::ServerConnection::new(config).map_err(io::Error::other)?;
+        Ok(Self {
+            session,
+            tcp,
+            plaintext: 0,
+            info: None,
+        })
+    }
+
+    pub fn wants_write(&self) -> bool {
+        self.session.wants_write()
+    }
+
+    pub fn has_buffered_input(&self) -> bool {
+        self.plaintext > 0
+    }
+
+    pub fn info(&mut self) -> TlsInfo {
+        let session = &self.session;
+        self.info
+            .get_or_insert_with(|| {
+                let peer_certificates: Vec<Vec<u8>> = session
+                    .peer_certificates()
+                    .unwrap_or(&[])
+                    .iter()
+                    .map(|cert| cert.0.clone())
+                    .collect();
+                TlsInfo {
+                    server_name: session.server_name().map(String::from),
+                    alpn_protocol: session.alpn_protocol().map(Vec::from),
+                    peer_subject: peer_certificates.first().and_then(|cert| subject(cert)),
+                    peer_certificates: Arc::new(peer_certificates),
+                }
+            })
+            .clone()
+    }
+
+    pub fn close(&mut self) {
+        self.session.send_close_notify();
+        self.flush().ok();
+    }
+
+    pub fn tcp(&mut self) -> &mut TcpStream {
+        &mut self.tcp
+    }
+
+    /// Write out as many pending TLS records as the socket takes
+    fn write_records(&mut self) -> io::Result<()> {
+        while self.session.wants_write() {
+            self.session.write_tls(&mut self.tcp)?;
+        }
+        Ok(())
+    }
+}
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.session.reader().read(buf) {
+                Ok(n) => {
+                    self.plaintext = self.plaintext.saturating_sub(n);
+                    return Ok(n);
+                }
+                // the peer went away without a `close_notify`, nothing more to read either way
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+            // out of plaintext, decrypt the next records from the socket
+            if self.session.read_tls(&mut self.tcp)? == 0 {
+                return Ok(0);
+            }
+            let state = match self.session.process_new_packets() {
+                Ok(state) => state,
+                Err(e) => {
+                    // let the peer know what went wrong
+                    self.write_records().ok();
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                }
+            };
+            self.plaintext = state.plaintext_bytes_to_read();
+            // answer the handshake, if that's where we are. Whatever doesn't fit
+            // is written once the socket is writable
+            match self.write_records() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                res => res?,
+            }
+        }
+    }
+}
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // only take more once the previous records are out, so nothing piles up
+        self.write_records()?;
+        let n = self.session.writer().write(buf)?;
+        match self.write_records() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            res => res?,
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_records()?;
+        self.tcp.flush()
+    }
+}
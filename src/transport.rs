@@ -0,0 +1,250 @@
+#[cfg(unix)]
+use libc;
+use mio::event::Source;
+use mio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use mio::net::{UnixListener, UnixStream};
+use mio::{Interest, Registry, Token};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+
+#[cfg(feature = "tls")]
+use tls::{TlsInfo, TlsStream};
+#[cfg(unix)]
+use unix::{self, PeerCredentials};
+
+/// Position of the listener that accepted a connection among the server's
+/// addresses, carried in the extensions of its requests
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ListenerIndex(pub usize);
+
+/// A socket accepting connections, bound to a TCP address or a Unix socket path
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+impl Listener {
+    /// Accept a connection, along with a description of its peer for logging.
+    /// Connections are plain until the server sets TLS up
+    pub fn accept(&self) -> io::Result<(Transport, String)> {
+        match *self {
+            Listener::Tcp(ref tcp) => tcp
+                .accept()
+                .map(|(sock, addr)| (Transport::Plain(sock), addr.to_string())),
+            #[cfg(unix)]
+            Listener::Unix(ref unix) => unix
+                .accept()
+                .map(|(sock, addr)| (Transport::Unix(sock), format!("{:?}", addr))),
+        }
+    }
+
+    /// The address of a TCP listener, `None` for a Unix socket
+    pub fn local_addr(&self) -> io::Result<Option<SocketAddr>> {
+        match *self {
+            Listener::Tcp(ref tcp) => tcp.local_addr().map(Some),
+            #[cfg(unix)]
+            Listener::Unix(_) => Ok(None),
+        }
+    }
+
+    /// Another handle to the same socket, registered separately with a `mio::Poll`
+    #[cfg(unix)]
+    pub fn try_clone(&self) -> io::Result<Self> {
+        use std::os::unix::io::FromRawFd;
+
+        let fd = unsafe { libc::dup(self.source_fd()) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(match *self {
+            Listener::Tcp(_) => Listener::Tcp(unsafe { TcpListener::from_raw_fd(fd) }),
+            Listener::Unix(_) => Listener::Unix(unsafe { UnixListener::from_raw_fd(fd) }),
+        })
+    }
+
+    #[cfg(unix)]
+    fn source_fd(&self) -> std::os::unix::io::RawFd {
+        use std::os::unix::io::AsRawFd;
+
+        match *self {
+            Listener::Tcp(ref tcp) => tcp.as_raw_fd(),
+            Listener::Unix(ref unix) => unix.as_raw_fd(),
+        }
+    }
+
+    fn source(&mut self) -> &mut dyn Source {
+        match *self {
+            Listener::Tcp(ref mut tcp) => tcp,
+            #[cfg(unix)]
+            Listener::Unix(ref mut unix) => unix,
+        }
+    }
+}
+impl Source for Listener {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.source().register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.source().reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.source().deregister(registry)
+    }
+}
+
+/// The byte stream of a connection, plain or encrypted
+pub(crate) enum Transport {
+    Plain(TcpStream),
+    /// Accepted on a Unix socket, always plain
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+}
+impl Transport {
+    /// Whether there's output that couldn't be written to the socket yet, which
+    /// `flush` retries once it's writable
+    pub fn wants_write(&self) -> bool {
+        match *self {
+            Transport::Plain(_) => false,
+            #[cfg(unix)]
+            Transport::Unix(_) => false,
+            #[cfg(feature = "tls")]
+            Transport::Tls(ref tls) => tls.wants_write(),
+        }
+    }
+
+    /// Whether the connection is encrypted
+    pub fn is_encrypted(&self) -> bool {
+        match *self {
+            Transport::Plain(_) => false,
+            #[cfg(unix)]
+            Transport::Unix(_) => false,
+            #[cfg(feature = "tls")]
+            Transport::Tls(_) => true,
+        }
+    }
+
+    /// Whether bytes already taken off the socket are waiting to be read. The
+    /// socket won't report them as readable again
+    pub fn has_buffered_input(&self) -> bool {
+        match *self {
+            Transport::Plain(_) => false,
+            #[cfg(unix)]
+            Transport::Unix(_) => false,
+            #[cfg(feature = "tls")]
+            Transport::Tls(ref tls) => tls.has_buffered_input(),
+        }
+    }
+
+    /// What was negotiated during the TLS handshake, for encrypted connections
+    #[cfg(feature = "tls")]
+    pub fn tls_info(&mut self) -> Option<TlsInfo> {
+        match *self {
+            Transport::Plain(_) => None,
+            #[cfg(unix)]
+            Transport::Unix(_) => None,
+            Transport::Tls(ref mut tls) => Some(tls.info()),
+        }
+    }
+
+    /// Tell the peer we're closing the connection, as far as the protocol has a
+    /// way to. Best effort, the connection is dropped right after
+    pub fn close(&mut self) {
+        match *self {
+            Transport::Plain(_) => {}
+            #[cfg(unix)]
+            Transport::Unix(_) => {}
+            #[cfg(feature = "tls")]
+            Transport::Tls(ref mut tls) => tls.close(),
+        }
+    }
+
+    /// Credentials of the process that connected, for connections accepted on
+    /// a Unix socket
+    #[cfg(unix)]
+    pub fn peer_credentials(&self) -> Option<PeerCredentials> {
+        match *self {
+            Transport::Unix(ref unix) => unix::peer_credentials(unix),
+            _ => None,
+        }
+    }
+
+    fn source(&mut self) -> &mut dyn Source {
+        match *self {
+            Transport::Plain(ref mut tcp) => tcp,
+            #[cfg(unix)]
+            Transport::Unix(ref mut unix) => unix,
+            #[cfg(feature = "tls")]
+            Transport::Tls(ref mut tls) => tls.tcp(),
+        }
+    }
+}
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Transport::Plain(ref mut tcp) => tcp.read(buf),
+            #[cfg(unix)]
+            Transport::Unix(ref mut unix) => unix.read(buf),
+            #[cfg(feature = "tls")]
+            Transport::Tls(ref mut tls) => tls.read(buf),
+        }
+    }
+}
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Transport::Plain(ref mut tcp) => tcp.write(buf),
+            #[cfg(unix)]
+            Transport::Unix(ref mut unix) => unix.write(buf),
+            #[cfg(feature = "tls")]
+            Transport::Tls(ref mut tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Transport::Plain(ref mut tcp) => tcp.flush(),
+            #[cfg(unix)]
+            Transport::Unix(ref mut unix) => unix.flush(),
+            #[cfg(feature = "tls")]
+            Transport::Tls(ref mut tls) => tls.flush(),
+        }
+    }
+}
+impl Source for Transport {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.source().register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.source().reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.source().deregister(registry)
+    }
+}
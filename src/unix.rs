@@ -0,0 +1,118 @@
+//! Unix domain socket listeners, and the credentials of the processes connecting
+use libc;
+use mio::net::UnixListener;
+use std;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// Credentials of the process on the other end of a Unix socket connection, see
+/// [`Request::peer_credentials`](struct.Request.html#method.peer_credentials)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// Only known on Linux and Android
+    pub pid: Option<i32>,
+}
+
+/// Bind a listener to the socket file at `path`. With `unlink` set, a socket left
+/// there, e.g. by a previous run, is removed first. The socket file is created
+/// with `permissions`, so it's never reachable with looser ones
+pub(crate) fn bind(
+    path: &Path,
+    unlink: bool,
+    permissions: Option<u32>,
+) -> io::Result<UnixListener> {
+    if unlink {
+        match fs::symlink_metadata(path) {
+            // anything else at the path is left alone, binding then fails
+            Ok(ref meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+            _ => {}
+        }
+    }
+    let mode = match permissions {
+        Some(mode) => mode,
+        None => return UnixListener::bind(path),
+    };
+    // the umask is process-wide: files other threads create meanwhile get it too,
+    // at worst more restrictive permissions than they'd have had
+    let umask = unsafe { libc::umask(!mode as libc::mode_t & 0o777) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    let listener = listener?;
+    // the umask can only remove permissions, set any special bits asked for too
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// Credentials of the peer of `socket`, `None` where the platform doesn't tell
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn peer_credentials<S: AsRawFd>(socket: &S) -> Option<PeerCredentials> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res != 0 {
+        warn!(
+            "Failed to get the peer credentials: {}",
+            io::Error::last_os_error()
+        );
+        return None;
+    }
+    Some(PeerCredentials {
+        uid: cred.uid,
+        gid: cred.gid,
+        pid: Some(cred.pid),
+    })
+}
+
+/// Credentials of the peer of `socket`, `None` where the platform doesn't tell
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+))]
+pub(crate) fn peer_credentials<S: AsRawFd>(socket: &S) -> Option<PeerCredentials> {
+    let mut uid = 0;
+    let mut gid = 0;
+    if unsafe { libc::getpeereid(socket.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        warn!(
+            "Failed to get the peer credentials: {}",
+            io::Error::last_os_error()
+        );
+        return None;
+    }
+    Some(PeerCredentials {
+        uid,
+        gid,
+        pid: None,
+    })
+}
+
+/// Credentials of the peer of `socket`, `None` where the platform doesn't tell
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+)))]
+pub(crate) fn peer_credentials<S: AsRawFd>(_socket: &S) -> Option<PeerCredentials> {
+    None
+}
@@ -0,0 +1,80 @@
+//! Connections taken over by handlers once they switched to another protocol
+use mio::net::TcpStream;
+use std;
+use std::io;
+use std::sync::Mutex;
+
+use errors::*;
+use {header, Body, Response};
+
+/// Takes over an upgraded connection, see `Upgraded::response`
+pub(crate) type Callback = Box<dyn FnOnce(Upgraded) + Send>;
+
+/// Carries the callback of an upgrade response in its extensions, until the
+/// response is written
+pub(crate) struct OnUpgrade(Mutex<Option<Callback>>);
+impl OnUpgrade {
+    pub fn take(self) -> Option<Callback> {
+        self.0.into_inner().unwrap()
+    }
+}
+
+/// A connection handed over to a handler after a `101 Switching Protocols`
+/// response, see [`Upgraded::response`](#method.response)
+pub struct Upgraded {
+    /// The socket of the connection, no longer registered with the server and
+    /// still non-blocking
+    pub stream: TcpStream,
+    /// Bytes the client sent after the request that were already read, the
+    /// start of the new protocol
+    pub buffered: Vec<u8>,
+}
+impl Upgraded {
+    /// A `101 Switching Protocols` response to `protocol`. Once it's written the
+    /// connection is handed over to `callback`, which runs on the event loop and
+    /// should move the stream elsewhere, e.g. to a thread of its own.
+    ///
+    /// Only plain TCP connections can be handed over, TLS and Unix socket
+    /// connections are closed once the response is written. Fails if `protocol`
+    /// isn't a valid header value.
+    ///
+    /// ```rust,no_run
+    /// # fn run() -> Result<(), Box<dyn std::error::Error>> {
+    /// mini_http::Server::new("127.0.0.1:3000")?
+    ///     .start(|_request| {
+    ///         mini_http::Upgraded::response("tunnel", |upgraded| {
+    ///             std::thread::spawn(move || {
+    ///                 let (_stream, _buffered) = upgraded.into_std().unwrap();
+    ///                 // speak the new protocol
+    ///             });
+    ///         })
+    ///         .unwrap()
+    ///     })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn response<F>(protocol: &str, callback: F) -> Result<Response<Body>>
+    where
+        F: 'static + FnOnce(Upgraded) + Send,
+    {
+        let mut response = Response::builder()
+            .status(101)
+            .header(header::CONNECTION, "upgrade")
+            .header(header::UPGRADE, protocol)
+            .body(Body::empty())?;
+        response
+            .extensions_mut()
+            .insert(OnUpgrade(Mutex::new(Some(Box::new(callback)))));
+        Ok(response)
+    }
+
+    /// The socket as a blocking `std::net::TcpStream`, along with the buffered bytes
+    #[cfg(unix)]
+    pub fn into_std(self) -> io::Result<(std::net::TcpStream, Vec<u8>)> {
+        use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+        let stream = unsafe { std::net::TcpStream::from_raw_fd(self.stream.into_raw_fd()) };
+        stream.set_nonblocking(false)?;
+        Ok((stream, self.buffered))
+    }
+}
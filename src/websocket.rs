@@ -0,0 +1,862 @@
+//! WebSocket (RFC 6455) on a connection upgraded from HTTP/1.1. Messages are
+//! handed to the callback returned by the server's WebSocket handler for the
+//! connection, which runs on the event loop.
+//!
+//! Messages can also be sent from other threads through a `WebSocketSender`,
+//! they're written by the event loop once it's woken up.
+//!
+//! Pings are answered right away, and a client that went quiet for the idle
+//! timeout is pinged in turn. Extensions and subprotocols aren't negotiated.
+use http;
+use mio::Token;
+use sha1_smol;
+use std;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use errors::*;
+use http_stream;
+use sse::Watcher;
+use transport::Transport;
+use RequestHead;
+
+/// Appended to the client's key to compute `Sec-WebSocket-Accept`
+const GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The only protocol version there is
+pub(crate) const VERSION: &str = "13";
+
+// opcodes
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+const FIN: u8 = 0x80;
+const RSV: u8 = 0x70;
+const MASK: u8 = 0x80;
+/// Largest frame header, with a 64 bit length and a masking key
+const MAX_HEADER_LEN: usize = 14;
+/// Largest payload of a control frame
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+// close codes
+const NORMAL_CLOSURE: u16 = 1000;
+const GOING_AWAY: u16 = 1001;
+const PROTOCOL_ERROR: u16 = 1002;
+/// Never sent, reported to callbacks when the connection ends without a close frame
+const ABNORMAL_CLOSURE: u16 = 1006;
+const INVALID_DATA: u16 = 1007;
+const MESSAGE_TOO_BIG: u16 = 1009;
+
+/// A failure of the protocol, the connection is closed with this close code
+type CloseResult<T> = std::result::Result<T, u16>;
+
+/// Receives the messages of a connection, see `Server::websocket`
+pub(crate) type Callback = Box<dyn FnMut(&mut WebSocket, Message)>;
+
+/// A message received from, or sent to, a WebSocket client
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    /// The connection switched to WebSocket. Callbacks get it once, before any
+    /// other message, e.g. to take a `WebSocket::sender`. Sending it does nothing
+    Open,
+    Text(String),
+    Binary(Vec<u8>),
+    /// The connection is closing, with the status code and reason of the close frame.
+    /// Callbacks get it once, also when the connection fails or ends without
+    /// a close frame (code `1006`)
+    Close(Option<u16>, String),
+}
+impl From<String> for Message {
+    fn from(text: String) -> Self {
+        Message::Text(text)
+    }
+}
+impl<'a> From<&'a str> for Message {
+    fn from(text: &'a str) -> Self {
+        Message::Text(text.to_string())
+    }
+}
+impl From<Vec<u8>> for Message {
+    fn from(data: Vec<u8>) -> Self {
+        Message::Binary(data)
+    }
+}
+
+/// The sending side of a WebSocket connection, handed to its callback along
+/// with each message. What's sent is written once the callback returns
+pub struct WebSocket {
+    /// Frames waiting to be written
+    out: Vec<u8>,
+    /// Set once a close frame is queued, nothing is sent after it
+    closing: bool,
+    /// Shared with the `WebSocketSender` handles of the connection
+    shared: Arc<Mutex<Shared>>,
+}
+impl WebSocket {
+    /// Send `message` to the client. Sending a `Message::Close` is the same as `close`
+    pub fn send<M: Into<Message>>(&mut self, message: M) {
+        match message.into() {
+            Message::Open => {}
+            Message::Text(text) => self.push_frame(TEXT, text.as_bytes()),
+            Message::Binary(data) => self.push_frame(BINARY, &data),
+            Message::Close(code, reason) => self.close(code.unwrap_or(NORMAL_CLOSURE), &reason),
+        }
+    }
+
+    /// Start the close handshake with `code` and `reason`, the connection closes
+    /// once the client answers. Messages received in the meantime are dropped
+    pub fn close(&mut self, code: u16, reason: &str) {
+        self.push_close(Some(code), reason);
+    }
+
+    /// Whether the close handshake has started
+    pub fn is_closing(&self) -> bool {
+        self.closing
+    }
+
+    /// A handle sending messages to the client from any thread, e.g. to push
+    /// updates without waiting for the client to send something
+    pub fn sender(&self) -> WebSocketSender {
+        WebSocketSender {
+            shared: self.shared.clone(),
+        }
+    }
+
+    fn push_close(&mut self, code: Option<u16>, reason: &str) {
+        let mut payload = Vec::new();
+        if let Some(code) = code {
+            payload.extend_from_slice(&code.to_be_bytes());
+            // control frames are limited in size, cut the reason at a character
+            let mut end = reason.len().min(MAX_CONTROL_PAYLOAD - 2);
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+            payload.extend_from_slice(&reason.as_bytes()[..end]);
+        }
+        self.push_frame(CLOSE, &payload);
+        self.closing = true;
+    }
+
+    /// Queue an unfragmented frame, servers don't mask them
+    fn push_frame(&mut self, opcode: u8, payload: &[u8]) {
+        if self.closing {
+            return;
+        }
+        self.out.push(FIN | opcode);
+        match payload.len() {
+            len if len < 126 => self.out.push(len as u8),
+            len if len <= 0xffff => {
+                self.out.push(126);
+                self.out.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                self.out.push(127);
+                self.out.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        self.out.extend_from_slice(payload);
+    }
+}
+
+/// State of a connection shared with its `WebSocketSender` handles
+struct Shared {
+    /// Messages sent through the handles, until the event loop takes them
+    queue: Vec<Message>,
+    /// Set once the close handshake started, or the connection is gone
+    closed: bool,
+    /// Wakes the event loop of the connection
+    watcher: Watcher,
+    /// Set once the event loop was woken up, until it takes the queue
+    notified: bool,
+}
+
+/// Sends messages to the client of a WebSocket connection from any thread, see
+/// `WebSocket::sender`. They're written by the event loop of the connection,
+/// after the ones the callback sent so far.
+///
+/// ```rust,no_run
+/// # fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// use mini_http::Message;
+///
+/// mini_http::Server::new("127.0.0.1:3000")?
+///     .websocket(|_head| {
+///         Some(|ws: &mut mini_http::WebSocket, message| {
+///             if let Message::Open = message {
+///                 let sender = ws.sender();
+///                 std::thread::spawn(move || {
+///                     while sender.send("tick") {
+///                         std::thread::sleep(std::time::Duration::from_secs(1));
+///                     }
+///                 });
+///             }
+///         })
+///     })
+///     .start(|_request| mini_http::Response::builder().status(404).body(Vec::new()).unwrap())?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct WebSocketSender {
+    shared: Arc<Mutex<Shared>>,
+}
+impl WebSocketSender {
+    /// Send `message` to the client. Sending a `Message::Close` is the same as
+    /// `close`. Returns `false` once the connection is closing or gone
+    pub fn send<M: Into<Message>>(&self, message: M) -> bool {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.closed {
+            return false;
+        }
+        let message = message.into();
+        if let Message::Close(..) = message {
+            shared.closed = true;
+        }
+        shared.queue.push(message);
+        if !shared.notified {
+            shared.notified = true;
+            shared.watcher.notify();
+        }
+        true
+    }
+
+    /// Start the close handshake with `code` and `reason`, see `WebSocket::close`
+    pub fn close(&self, code: u16, reason: &str) {
+        self.send(Message::Close(Some(code), reason.to_string()));
+    }
+
+    /// Whether the connection is closing or gone
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().unwrap().closed
+    }
+}
+
+/// The WebSocket side of an upgraded connection
+pub(crate) struct Session {
+    /// Received bytes that don't make a complete frame yet
+    read_buf: Vec<u8>,
+    socket: WebSocket,
+    /// Bytes of `socket.out` written so far
+    written: usize,
+    /// Set when bytes were written since the last time it was cleared, see
+    /// `Connection::update_deadline`
+    pub wrote: bool,
+    callback: Callback,
+    /// Opcode and payload so far of a message sent in fragments
+    fragmented: Option<(u8, Vec<u8>)>,
+    /// Set once the client's close frame was received, or the connection failed.
+    /// Nothing is read after it
+    close_received: bool,
+    /// Set once the callback got its `Message::Close`
+    notified: bool,
+    /// Set when the client was pinged and nothing was received since
+    pinged: bool,
+    max_message_size: usize,
+}
+impl Session {
+    /// The WebSocket side of a connection, `response` is the `101 Switching Protocols`
+    /// accepting the handshake which is written before anything else. The callback
+    /// gets `Message::Open` right away. `watcher` wakes the event loop when messages
+    /// are sent from elsewhere
+    pub fn new(
+        response: Vec<u8>,
+        callback: Callback,
+        max_message_size: usize,
+        watcher: Watcher,
+    ) -> Self {
+        let shared = Shared {
+            queue: Vec::new(),
+            closed: false,
+            watcher,
+            notified: false,
+        };
+        let mut session = Self {
+            read_buf: Vec::new(),
+            socket: WebSocket {
+                out: response,
+                closing: false,
+                shared: Arc::new(Mutex::new(shared)),
+            },
+            written: 0,
+            wrote: false,
+            callback,
+            fragmented: None,
+            close_received: false,
+            notified: false,
+            pinged: false,
+            max_message_size,
+        };
+        (session.callback)(&mut session.socket, Message::Open);
+        session
+    }
+
+    /// Whether more bytes should be read, reading pauses while a whole frame
+    /// of the largest size is buffered
+    pub fn wants_more(&self) -> bool {
+        !self.close_received && self.read_buf.len() < self.max_message_size + MAX_HEADER_LEN
+    }
+
+    pub fn receive_chunk(&mut self, chunk: &[u8]) {
+        self.read_buf.extend_from_slice(chunk);
+        self.pinged = false;
+    }
+
+    /// Hand the messages received so far to the callback and write as much as
+    /// `stream` takes.
+    /// Returns `true` once the connection should be closed
+    pub fn serve(&mut self, token: Token, stream: &mut Transport) -> bool {
+        self.take_sent();
+        if !self.close_received {
+            if let Err(code) = self.process() {
+                error!(
+                    "{:?} - WebSocket protocol error, closing with {}",
+                    token, code
+                );
+                self.fail(code);
+            }
+        }
+        if self.socket.closing || self.close_received {
+            self.socket.shared.lock().unwrap().closed = true;
+        }
+        if let Err(e) = self.write(stream) {
+            error!(
+                "{:?} - Encountered error while writing to socket: {:?}",
+                token, e
+            );
+            return true;
+        }
+        // the server closes the connection once both sides sent their close frame
+        !self.wants_write() && self.close_received && self.socket.closing
+    }
+
+    /// Queue the messages sent through `WebSocketSender` handles
+    fn take_sent(&mut self) {
+        let sent = {
+            let mut shared = self.socket.shared.lock().unwrap();
+            shared.notified = false;
+            std::mem::take(&mut shared.queue)
+        };
+        for message in sent {
+            self.socket.send(message);
+        }
+    }
+
+    /// Close the connection, once the client answers the close frame
+    pub fn drain(&mut self) {
+        self.socket.close(GOING_AWAY, "");
+    }
+
+    /// Ping a client that went quiet, to tell whether it's still there.
+    /// Returns `false` if it didn't answer the previous ping, or the close
+    /// handshake didn't complete, the connection should be closed then
+    pub fn ping(&mut self) -> bool {
+        if self.pinged || self.socket.closing {
+            return false;
+        }
+        self.pinged = true;
+        self.socket.push_frame(PING, &[]);
+        true
+    }
+
+    /// Whether there's something to write once the socket is writable
+    pub fn wants_write(&self) -> bool {
+        self.written < self.socket.out.len()
+    }
+
+    /// Handle the complete frames in `read_buf`
+    fn process(&mut self) -> CloseResult<()> {
+        let mut buf = std::mem::take(&mut self.read_buf);
+        let mut pos = 0;
+        let res = self.frames(&buf, &mut pos);
+        // what's left of a frame still arriving moves to the front
+        buf.drain(..pos);
+        self.read_buf = buf;
+        res
+    }
+
+    /// Handle the complete frames in `buf` from `pos` on, moving `pos` past them
+    fn frames(&mut self, buf: &[u8], pos: &mut usize) -> CloseResult<()> {
+        while !self.close_received {
+            let frame = &buf[*pos..];
+            let (header_len, payload_len) = match frame_len(frame, self.max_message_size)? {
+                Some(len) if frame.len() >= len.0 + len.1 => len,
+                _ => return Ok(()),
+            };
+            let mut payload = frame[header_len..header_len + payload_len].to_vec();
+            let key = &frame[header_len - 4..header_len];
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= key[i & 3];
+            }
+            *pos += header_len + payload_len;
+            self.frame(frame[0], payload)?;
+        }
+        Ok(())
+    }
+
+    fn frame(&mut self, first: u8, payload: Vec<u8>) -> CloseResult<()> {
+        let fin = first & FIN != 0;
+        let opcode = first & 0x0f;
+        match opcode {
+            CLOSE | PING | PONG if !fin || payload.len() > MAX_CONTROL_PAYLOAD => {
+                return Err(PROTOCOL_ERROR);
+            }
+            CLOSE => self.closed(&payload)?,
+            PING => self.socket.push_frame(PONG, &payload),
+            PONG => {}
+            TEXT | BINARY => {
+                // control frames are the only ones allowed between fragments
+                if self.fragmented.is_some() {
+                    return Err(PROTOCOL_ERROR);
+                }
+                if fin {
+                    self.message(opcode, payload)?;
+                } else {
+                    self.fragmented = Some((opcode, payload));
+                }
+            }
+            CONTINUATION => {
+                let (opcode, mut data) = self.fragmented.take().ok_or(PROTOCOL_ERROR)?;
+                if data.len() + payload.len() > self.max_message_size {
+                    return Err(MESSAGE_TOO_BIG);
+                }
+                data.extend_from_slice(&payload);
+                if fin {
+                    self.message(opcode, data)?;
+                } else {
+                    self.fragmented = Some((opcode, data));
+                }
+            }
+            _ => return Err(PROTOCOL_ERROR),
+        }
+        Ok(())
+    }
+
+    /// Hand a complete message to the callback
+    fn message(&mut self, opcode: u8, data: Vec<u8>) -> CloseResult<()> {
+        let message = if opcode == TEXT {
+            Message::Text(String::from_utf8(data).map_err(|_| INVALID_DATA)?)
+        } else {
+            Message::Binary(data)
+        };
+        if !self.socket.closing {
+            (self.callback)(&mut self.socket, message);
+        }
+        Ok(())
+    }
+
+    /// Handle the client's close frame, answering it with the same code unless
+    /// the server started closing
+    fn closed(&mut self, payload: &[u8]) -> CloseResult<()> {
+        let (code, reason) = match payload.len() {
+            0 => (None, ""),
+            1 => return Err(PROTOCOL_ERROR),
+            _ => {
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                if !is_valid_close_code(code) {
+                    return Err(PROTOCOL_ERROR);
+                }
+                let reason = std::str::from_utf8(&payload[2..]).map_err(|_| INVALID_DATA)?;
+                (Some(code), reason)
+            }
+        };
+        self.close_received = true;
+        if !self.socket.closing {
+            self.socket.push_close(code, "");
+        }
+        self.notify(Message::Close(code, reason.to_string()));
+        Ok(())
+    }
+
+    /// Close the connection after a protocol error
+    fn fail(&mut self, code: u16) {
+        self.close_received = true;
+        self.socket.close(code, "");
+        self.notify(Message::Close(Some(code), String::new()));
+    }
+
+    /// Tell the callback the connection is closing, once
+    fn notify(&mut self, message: Message) {
+        if !self.notified {
+            self.notified = true;
+            (self.callback)(&mut self.socket, message);
+        }
+    }
+
+    /// Write queued frames. Returns `false` once `stream` doesn't take more
+    fn write(&mut self, stream: &mut Transport) -> io::Result<bool> {
+        while self.written < self.socket.out.len() {
+            match stream.write(&self.socket.out[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.written += n;
+                    self.wrote = true;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+        self.socket.out.clear();
+        self.written = 0;
+        match stream.flush() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+            Ok(()) => Ok(true),
+        }
+    }
+}
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.socket.shared.lock().unwrap().closed = true;
+        self.notify(Message::Close(Some(ABNORMAL_CLOSURE), String::new()));
+    }
+}
+
+/// Whether `request` asks to switch to WebSocket
+pub(crate) fn is_upgrade(request: &RequestHead) -> bool {
+    request.method() == http::Method::GET
+        && request.version() == http::Version::HTTP_11
+        && request
+            .headers()
+            .get_all(http::header::UPGRADE)
+            .iter()
+            .filter_map(|val| val.to_str().ok())
+            .flat_map(|val| val.split(','))
+            .any(|protocol| protocol.trim().eq_ignore_ascii_case("websocket"))
+        && http_stream::has_connection_option(request, "upgrade")
+}
+
+/// The `101 Switching Protocols` accepting the handshake of `request`
+pub(crate) fn handshake(request: &RequestHead) -> Result<Vec<u8>> {
+    let headers = request.headers();
+    if headers
+        .get_all(http::header::SEC_WEBSOCKET_VERSION)
+        .iter()
+        .count()
+        != 1
+        || headers[http::header::SEC_WEBSOCKET_VERSION] != VERSION
+    {
+        bail_fmt!(
+            ErrorKind::UnsupportedWebSocketVersion,
+            "Expected WebSocket version {}",
+            VERSION
+        );
+    }
+    let key = match headers.get(http::header::SEC_WEBSOCKET_KEY) {
+        Some(key) if is_valid_key(key.as_bytes()) => key,
+        _ => bail_fmt!(ErrorKind::MalformedHttpRequest, "Invalid Sec-WebSocket-Key"),
+    };
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID);
+    Ok(format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        base64_encode(&sha1.digest().bytes())
+    )
+    .into_bytes())
+}
+
+/// Header and payload length of the frame starting `buf`, once enough of it
+/// is received to tell
+fn frame_len(buf: &[u8], max_message_size: usize) -> CloseResult<Option<(usize, usize)>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    // no extension was negotiated that would use the reserved bits,
+    // and clients must mask their frames
+    if buf[0] & RSV != 0 || buf[1] & MASK == 0 {
+        return Err(PROTOCOL_ERROR);
+    }
+    let (len_bytes, len) = match buf[1] & 0x7f {
+        126 => (2, None),
+        127 => (8, None),
+        len => (0, Some(u64::from(len))),
+    };
+    if buf.len() < 2 + len_bytes {
+        return Ok(None);
+    }
+    let len = len.unwrap_or_else(|| {
+        buf[2..2 + len_bytes]
+            .iter()
+            .fold(0, |len, &byte| len << 8 | u64::from(byte))
+    });
+    if len > max_message_size as u64 {
+        return Err(MESSAGE_TOO_BIG);
+    }
+    Ok(Some((2 + len_bytes + 4, len as usize)))
+}
+
+/// Codes a close frame may carry, the others are reserved or unassigned
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999)
+}
+
+/// Whether `key` is the base64 encoding of 16 bytes
+fn is_valid_key(key: &[u8]) -> bool {
+    key.len() == 24
+        && key[22..] == b"=="[..]
+        && key[..22]
+            .iter()
+            .all(|&c| c.is_ascii_alphanumeric() || c == b'+' || c == b'/')
+}
+
+/// Encode `data` as padded base64
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| {
+            bits | u32::from(byte) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mio;
+    use sse::Wakeups;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// A session whose callback records the messages it gets, and those
+    /// messages. The poll has to outlive the session for its waker to work
+    fn new_session(max_message_size: usize) -> (Session, Rc<RefCell<Vec<Message>>>, mio::Poll) {
+        let poll = mio::Poll::new().unwrap();
+        let waker = mio::Waker::new(poll.registry(), Token(0)).unwrap();
+        let watcher = Watcher::new(Wakeups::new(Arc::new(waker)), Token(1), 0);
+        let messages = Rc::new(RefCell::new(Vec::new()));
+        let received = messages.clone();
+        let callback = Box::new(move |_: &mut WebSocket, message| {
+            received.borrow_mut().push(message);
+        });
+        let session = Session::new(Vec::new(), callback, max_message_size, watcher);
+        (session, messages, poll)
+    }
+
+    /// A frame as a client sends it, masked
+    fn masked(first: u8, payload: &[u8]) -> Vec<u8> {
+        let key = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![first];
+        if payload.len() < 126 {
+            frame.push(MASK | payload.len() as u8);
+        } else {
+            frame.push(MASK | 126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        frame.extend_from_slice(&key);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i & 3]));
+        frame
+    }
+
+    /// Feed `input` to the session and process it, returning what it queued to send
+    fn receive(session: &mut Session, input: &[u8]) -> Vec<u8> {
+        session.receive_chunk(input);
+        if let Err(code) = session.process() {
+            session.fail(code);
+        }
+        std::mem::take(&mut session.socket.out)
+    }
+
+    fn close_frame(code: u16) -> Vec<u8> {
+        let mut frame = vec![FIN | CLOSE, 2];
+        frame.extend_from_slice(&code.to_be_bytes());
+        frame
+    }
+
+    #[test]
+    fn base64() {
+        // RFC 4648 section 10
+        for &(data, encoded) in &[
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(base64_encode(data.as_bytes()), encoded);
+        }
+    }
+
+    #[test]
+    fn handshake_accept_key() {
+        // RFC 6455 section 1.3
+        let request = http::Request::builder()
+            .method("GET")
+            .uri("/chat")
+            .header("Upgrade", "websocket")
+            .header("Connection", "Upgrade")
+            .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .header("Sec-WebSocket-Version", "13")
+            .body(())
+            .unwrap();
+        assert!(is_upgrade(&request));
+        let response = String::from_utf8(handshake(&request).unwrap()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    }
+
+    #[test]
+    fn handshake_rejects_bad_keys_and_versions() {
+        let request = |key: &str, version: &str| {
+            http::Request::builder()
+                .header("Sec-WebSocket-Key", key)
+                .header("Sec-WebSocket-Version", version)
+                .body(())
+                .unwrap()
+        };
+        assert!(handshake(&request("dGhlIHNhbXBsZSBub25jZQ==", "8")).is_err());
+        assert!(handshake(&request("dGhlIHNhbXBsZSBub25jZQ", "13")).is_err());
+        assert!(handshake(&request("dGhlIHNhbXBsZSBub25jZQ=!", "13")).is_err());
+        assert!(handshake(&request("dGhl*HNhbXBsZSBub25jZQ==", "13")).is_err());
+    }
+
+    #[test]
+    fn masked_text() {
+        let (mut session, messages, _poll) = new_session(1024);
+        // RFC 6455 section 5.7, a masked "Hello", in two reads
+        let frame = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        assert!(receive(&mut session, &frame[..4]).is_empty());
+        assert!(receive(&mut session, &frame[4..]).is_empty());
+        assert_eq!(
+            *messages.borrow(),
+            vec![Message::Open, Message::Text("Hello".into())]
+        );
+    }
+
+    #[test]
+    fn unmasked_frame_fails() {
+        let (mut session, messages, _poll) = new_session(1024);
+        let out = receive(&mut session, &[0x81, 0x05, b'H', b'e', b'l', b'l', b'o']);
+        assert_eq!(out, close_frame(PROTOCOL_ERROR));
+        assert_eq!(
+            messages.borrow().last(),
+            Some(&Message::Close(Some(PROTOCOL_ERROR), String::new()))
+        );
+    }
+
+    #[test]
+    fn fragmented_message_with_interleaved_ping() {
+        let (mut session, messages, _poll) = new_session(1024);
+        let mut input = masked(BINARY, b"\x01\x02");
+        input.extend(masked(FIN | PING, b"ping"));
+        input.extend(masked(CONTINUATION, b"\x03"));
+        input.extend(masked(FIN | CONTINUATION, b"\x04\x05"));
+        let out = receive(&mut session, &input);
+        // the ping is answered right away, unmasked
+        assert_eq!(out, b"\x8a\x04ping");
+        assert_eq!(
+            messages.borrow()[1..],
+            [Message::Binary(vec![1, 2, 3, 4, 5])]
+        );
+    }
+
+    #[test]
+    fn fragmentation_errors() {
+        // a continuation without a message to continue
+        let (mut session, _, _poll) = new_session(1024);
+        let out = receive(&mut session, &masked(FIN | CONTINUATION, b"x"));
+        assert_eq!(out, close_frame(PROTOCOL_ERROR));
+        // a new message before the fragmented one ended
+        let (mut session, _, _poll) = new_session(1024);
+        let mut input = masked(TEXT, b"a");
+        input.extend(masked(FIN | TEXT, b"b"));
+        assert_eq!(receive(&mut session, &input), close_frame(PROTOCOL_ERROR));
+        // fragments adding up to more than the limit
+        let (mut session, _, _poll) = new_session(4);
+        let mut input = masked(BINARY, b"abc");
+        input.extend(masked(FIN | CONTINUATION, b"de"));
+        assert_eq!(receive(&mut session, &input), close_frame(MESSAGE_TOO_BIG));
+    }
+
+    #[test]
+    fn control_frame_errors() {
+        let long = [0; MAX_CONTROL_PAYLOAD + 1];
+        for frame in &[
+            masked(PING, b""),
+            masked(FIN | PING, &long),
+            masked(FIN | 0x3, b""),
+            masked(FIN | RSV | TEXT, b""),
+            masked(FIN | CLOSE, b"\x03"),
+            masked(FIN | CLOSE, &1005u16.to_be_bytes()),
+        ] {
+            let (mut session, _, _poll) = new_session(1024);
+            assert_eq!(receive(&mut session, frame), close_frame(PROTOCOL_ERROR));
+        }
+    }
+
+    #[test]
+    fn invalid_utf8_and_oversize() {
+        let (mut session, _, _poll) = new_session(1024);
+        let out = receive(&mut session, &masked(FIN | TEXT, b"\xff\xfe"));
+        assert_eq!(out, close_frame(INVALID_DATA));
+        let (mut session, _, _poll) = new_session(8);
+        let out = receive(&mut session, &masked(FIN | BINARY, &[0; 9]));
+        assert_eq!(out, close_frame(MESSAGE_TOO_BIG));
+    }
+
+    #[test]
+    fn close_handshake() {
+        let (mut session, messages, _poll) = new_session(1024);
+        let mut payload = 1001u16.to_be_bytes().to_vec();
+        payload.extend_from_slice(b"bye");
+        let mut input = masked(FIN | CLOSE, &payload);
+        // nothing is read after the close frame
+        input.extend(masked(FIN | TEXT, b"late"));
+        let out = receive(&mut session, &input);
+        // answered with the same code
+        assert_eq!(out, close_frame(1001));
+        assert_eq!(
+            messages.borrow()[1..],
+            [Message::Close(Some(1001), "bye".into())]
+        );
+        drop(session);
+        // the callback got its close message once
+        assert_eq!(messages.borrow().len(), 2);
+    }
+
+    #[test]
+    fn sent_frames() {
+        let (mut session, _, _poll) = new_session(1024);
+        session.socket.send("hi");
+        session.socket.send(vec![0u8; 200]);
+        session.socket.send(vec![0u8; 70000]);
+        let out = std::mem::take(&mut session.socket.out);
+        assert_eq!(out[..4], b"\x81\x02hi"[..]);
+        assert_eq!(out[4..8], [0x82, 126, 0, 200]);
+        let big = &out[8 + 200..];
+        assert_eq!(big[..2], [0x82, 127]);
+        assert_eq!(big[2..10], 70000u64.to_be_bytes());
+        assert_eq!(big.len(), 10 + 70000);
+    }
+
+    #[test]
+    fn sender_queues_until_closing() {
+        let (mut session, _, _poll) = new_session(1024);
+        let sender = session.socket.sender();
+        assert!(sender.send("from elsewhere"));
+        session.take_sent();
+        assert_eq!(session.socket.out, b"\x81\x0efrom elsewhere");
+        sender.close(NORMAL_CLOSURE, "");
+        assert!(sender.is_closed());
+        assert!(!sender.send("too late"));
+        session.take_sent();
+        assert!(session.socket.is_closing());
+        drop(session);
+        assert!(!sender.send("gone"));
+    }
+}